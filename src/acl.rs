@@ -0,0 +1,411 @@
+use regex::Regex;
+use std::net::IpAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// An address range such as `10.0.0.0/8` or `fe80::/10`.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(s: &str) -> Result<Cidr, String> {
+        let mut split = s.splitn(2, '/');
+        let addr = split
+            .next()
+            .unwrap_or_default()
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address `{}`", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match split.next() {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("invalid prefix length in `{}`", s))?,
+            None => max,
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::max_value()
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::max_value()
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Unwraps IPv4-mapped IPv6 addresses so `::ffff:127.0.0.1` is treated as loopback.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(((u32::from(hi) << 16) | u32::from(lo)).into())
+            }
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+const PRIVATE_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+lazy_static! {
+    static ref PRIVATE: Vec<Cidr> = PRIVATE_RANGES
+        .iter()
+        .map(|r| Cidr::parse(r).unwrap())
+        .collect();
+}
+
+/// Whether `ip` is private, loopback, link-local, multicast, for benchmarking or
+/// unspecified.
+pub fn is_private(ip: IpAddr) -> bool {
+    PRIVATE.iter().any(|c| c.contains(ip))
+}
+
+/// Lowercases `host` and drops the trailing dot of a fully qualified name, so that
+/// `Example.COM.` is the same host as `example.com`.
+pub fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+#[derive(Debug, Clone)]
+pub enum HostPattern {
+    Exact(String),
+    /// `.example.com` matches `example.com` and every subdomain.
+    Suffix(String),
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn parse(s: &str) -> Result<HostPattern, String> {
+        if s.starts_with('~') {
+            Regex::new(&s[1..])
+                .map(HostPattern::Regex)
+                .map_err(|e| format!("invalid regex `{}`: {}", &s[1..], e))
+        } else if s.starts_with('.') {
+            Ok(HostPattern::Suffix(normalize_host(s)))
+        } else {
+            Ok(HostPattern::Exact(normalize_host(s)))
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = normalize_host(host);
+        match self {
+            HostPattern::Exact(h) => host == *h,
+            HostPattern::Suffix(s) => host.ends_with(s.as_str()) || host == s[1..],
+            HostPattern::Regex(r) => r.is_match(&host),
+        }
+    }
+}
+
+/// One `acl` line:
+/// `acl allow|deny [name=N] [src=CIDR,...] [host=H,...] [dst=CIDR|private,...]
+/// [port=P|P-Q,...] [method=M,...]`.
+///
+/// A rule matches when every condition it lists matches.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub name: String,
+    pub action: Action,
+    clients: Vec<Cidr>,
+    hosts: Vec<HostPattern>,
+    destinations: Vec<Cidr>,
+    ports: Vec<(u16, u16)>,
+    methods: Vec<String>,
+}
+
+impl Rule {
+    pub fn parse(args: &[&str], index: usize) -> Result<Rule, String> {
+        let action = match args.get(0).map(|s| *s) {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            _ => return Err("expected allow or deny".to_string()),
+        };
+
+        let mut rule = Rule {
+            name: format!("#{}", index + 1),
+            action,
+            clients: vec![],
+            hosts: vec![],
+            destinations: vec![],
+            ports: vec![],
+            methods: vec![],
+        };
+
+        for condition in args[1..].iter() {
+            let mut split = condition.splitn(2, '=');
+            let key = split.next().unwrap_or_default();
+            let values = split
+                .next()
+                .ok_or_else(|| format!("expected key=value, found `{}`", condition))?;
+
+            if key == "name" {
+                rule.name = values.to_string();
+                continue;
+            }
+            for value in values.split(',') {
+                match key {
+                    "src" => rule.clients.push(Cidr::parse(value)?),
+                    "host" => rule.hosts.push(HostPattern::parse(value)?),
+                    "dst" if value == "private" => rule.destinations.extend(PRIVATE.iter()),
+                    "dst" => rule.destinations.push(Cidr::parse(value)?),
+                    "port" => rule.ports.push(parse_port_range(value)?),
+                    "method" => rule.methods.push(value.to_uppercase()),
+                    _ => return Err(format!("unknown acl condition `{}`", key)),
+                }
+            }
+        }
+
+        Ok(rule)
+    }
+
+    fn matches(&self, target: &Target) -> bool {
+        (self.clients.is_empty() || self.clients.iter().any(|c| c.contains(target.client)))
+            && (self.hosts.is_empty() || self.hosts.iter().any(|h| h.matches(target.host)))
+            && (self.destinations.is_empty()
+                || target
+                    .addrs
+                    .iter()
+                    .any(|ip| self.destinations.iter().any(|c| c.contains(*ip))))
+            && (self.ports.is_empty()
+                || self
+                    .ports
+                    .iter()
+                    .any(|(lo, hi)| *lo <= target.port && target.port <= *hi))
+            && (self.methods.is_empty() || self.methods.iter().any(|m| m == target.method))
+    }
+
+    /// Whether the `dst=` ranges cover every private address of `target`.
+    fn allows_private(&self, target: &Target) -> bool {
+        target
+            .addrs
+            .iter()
+            .filter(|ip| is_private(**ip))
+            .all(|ip| self.destinations.iter().any(|c| c.contains(*ip)))
+    }
+}
+
+fn parse_port_range(s: &str) -> Result<(u16, u16), String> {
+    let mut split = s.splitn(2, '-');
+    let lo = split
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(|| format!("invalid port `{}`", s))?;
+    let hi = match split.next() {
+        Some(p) => p.parse().map_err(|_| format!("invalid port `{}`", s))?,
+        None => lo,
+    };
+    Ok((lo, hi))
+}
+
+/// What a request is asking for, as seen by the ACL.
+#[derive(Debug)]
+pub struct Target<'a> {
+    pub client: IpAddr,
+    pub host: &'a str,
+    pub addrs: &'a [IpAddr],
+    pub port: u16,
+    pub method: &'a str,
+}
+
+/// Ordered rules where the first match decides.
+///
+/// Destinations resolving to private addresses are denied unless `acl_allow_private` is on
+/// or the matching allow rule names them with `dst=`. When nothing matches, everything else
+/// gets the default action.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    pub rules: Vec<Rule>,
+    pub default: Action,
+    pub allow_private: bool,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl {
+            rules: vec![],
+            default: Action::Allow,
+            allow_private: false,
+        }
+    }
+}
+
+impl Acl {
+    /// Returns the name of the rule that denied `target`.
+    pub fn check(&self, target: &Target) -> Result<(), String> {
+        let private = !self.allow_private && target.addrs.iter().any(|ip| is_private(*ip));
+        let (name, action) = match self.rules.iter().find(|r| r.matches(target)) {
+            Some(rule)
+                if private && rule.action == Action::Allow && !rule.allows_private(target) =>
+            {
+                ("default-private", Action::Deny)
+            }
+            Some(rule) => (rule.name.as_str(), rule.action),
+            None if private => ("default-private", Action::Deny),
+            None => ("default", self.default),
+        };
+
+        match action {
+            Action::Allow => Ok(()),
+            Action::Deny => Err(name.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_v4() {
+        let cidr = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(cidr.contains(ip("10.1.255.7")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains(ip("203.0.113.9")));
+        assert!(Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.1")));
+        assert!(!Cidr::parse("192.0.2.1").unwrap().contains(ip("192.0.2.2")));
+        assert!(!cidr.contains(ip("::1")));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+    }
+
+    #[test]
+    fn cidr_v6() {
+        let cidr = Cidr::parse("fe80::/10").unwrap();
+        assert!(cidr.contains(ip("fe80::1")));
+        assert!(cidr.contains(ip("febf::1")));
+        assert!(!cidr.contains(ip("fec0::1")));
+        assert!(Cidr::parse("::/0").unwrap().contains(ip("2001:db8::1")));
+        assert!(!cidr.contains(ip("10.0.0.1")));
+        assert!(Cidr::parse("::/129").is_err());
+    }
+
+    #[test]
+    fn mapped_ipv4() {
+        assert!(is_private(ip("::ffff:127.0.0.1")));
+        assert!(is_private(ip("::ffff:10.0.0.1")));
+        assert!(!is_private(ip("::ffff:203.0.113.9")));
+        assert!(Cidr::parse("192.0.2.0/24")
+            .unwrap()
+            .contains(ip("::ffff:192.0.2.5")));
+    }
+
+    #[test]
+    fn private_ranges() {
+        for private in &[
+            "127.0.0.1",
+            "169.254.169.254",
+            "198.18.0.1",
+            "224.0.0.251",
+            "::1",
+        ] {
+            assert!(is_private(ip(private)), "{}", private);
+        }
+        assert!(is_private(ip("ff02::1")));
+        assert!(!is_private(ip("8.8.8.8")));
+        assert!(!is_private(ip("2001:4860::8888")));
+    }
+
+    #[test]
+    fn host_patterns() {
+        let exact = HostPattern::parse("Blocked.Example").unwrap();
+        assert!(exact.matches("blocked.example"));
+        assert!(exact.matches("blocked.example."));
+        assert!(exact.matches("BLOCKED.EXAMPLE."));
+        assert!(!exact.matches("www.blocked.example"));
+
+        let suffix = HostPattern::parse(".example.com").unwrap();
+        assert!(suffix.matches("example.com"));
+        assert!(suffix.matches("a.b.example.com."));
+        assert!(!suffix.matches("notexample.com"));
+
+        let regex = HostPattern::parse("~^admin\\.").unwrap();
+        assert!(regex.matches("Admin.example.com"));
+        assert!(!regex.matches("www.admin.example"));
+    }
+
+    fn check(acl: &Acl, client: &str, host: &str, addr: &str) -> Result<(), String> {
+        acl.check(&Target {
+            client: ip(client),
+            host,
+            addrs: &[ip(addr)],
+            port: 80,
+            method: "GET",
+        })
+    }
+
+    #[test]
+    fn private_targets() {
+        let mut acl = Acl::default();
+        acl.rules
+            .push(Rule::parse(&["allow", "src=10.0.0.0/8"], 0).unwrap());
+        let denied = check(&acl, "10.1.1.1", "internal", "127.0.0.1");
+        assert_eq!(denied, Err("default-private".to_string()));
+        assert!(check(&acl, "10.1.1.1", "example.com", "203.0.113.9").is_ok());
+
+        acl.rules
+            .insert(0, Rule::parse(&["allow", "dst=127.0.0.0/8"], 0).unwrap());
+        assert!(check(&acl, "10.1.1.1", "internal", "127.0.0.1").is_ok());
+        assert!(check(&acl, "10.1.1.1", "internal", "192.168.0.1").is_err());
+
+        acl.allow_private = true;
+        assert!(check(&acl, "10.1.1.1", "internal", "192.168.0.1").is_ok());
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let acl = Acl {
+            rules: vec![
+                Rule::parse(&["deny", "name=no-post", "method=POST"], 0).unwrap(),
+                Rule::parse(&["allow", "host=.example.com", "port=80-443"], 1).unwrap(),
+            ],
+            default: Action::Deny,
+            allow_private: false,
+        };
+        assert!(check(&acl, "203.0.113.1", "www.example.com.", "203.0.113.9").is_ok());
+        let denied = check(&acl, "203.0.113.1", "example.org", "203.0.113.9");
+        assert_eq!(denied, Err("default".to_string()));
+    }
+}
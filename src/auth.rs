@@ -0,0 +1,576 @@
+use crate::{parse_uri, upstream, Request};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Credentials kept as already checked before the cache starts over.
+const MAX_VERIFIED: usize = 1024;
+
+/// Users and password hashes read from an htpasswd file.
+///
+/// Supported hashes are bcrypt (`$2y$`, `$2a$`, `$2b$`), `{SHA}` and plain text.
+#[derive(Debug, Clone, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+    /// Salted digests of credentials bcrypt already accepted, so a client sending the same
+    /// ones with every request pays for bcrypt once.
+    verified: Arc<Mutex<HashSet<String>>>,
+    salt: String,
+    /// A bcrypt hash as costly as the file's, checked for unknown users so that they take
+    /// as long to refuse as known ones.
+    dummy: Option<String>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &str) -> Result<Htpasswd, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("reading htpasswd {} failed: {:?}", path, e))?;
+
+        let users = text
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| {
+                let mut split = l.splitn(2, ':');
+                let user = split.next()?;
+                let hash = split.next()?;
+                Some((user.to_string(), hash.to_string()))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let cost = users
+            .values()
+            .filter(|hash| is_bcrypt(hash))
+            .filter_map(|hash| hash.get(4..6)?.parse::<u32>().ok())
+            .max();
+        let dummy = match cost {
+            Some(cost) => Some(
+                bcrypt::hash(format!("{:x}", rand::random::<u64>()), cost)
+                    .map_err(|e| format!("hashing for {} failed: {:?}", path, e))?,
+            ),
+            None => None,
+        };
+
+        Ok(Htpasswd {
+            users,
+            verified: Arc::default(),
+            salt: format!("{:x}", rand::random::<u64>()),
+            dummy,
+        })
+    }
+
+    fn verified_key(&self, user: &str, password: &str) -> String {
+        let digest = sha1::Sha1::from(format!("{}:{}:{}", self.salt, user, password)).digest();
+        base64::encode(&digest.bytes())
+    }
+
+    /// Whether checking `password` means running bcrypt, which takes long enough that it
+    /// belongs off the reactor.
+    pub fn is_slow(&self, user: &str, password: &str) -> bool {
+        let hash = self.users.get(user).or_else(|| self.dummy.as_ref());
+        hash.map_or(false, |hash| is_bcrypt(hash))
+            && !self
+                .verified
+                .lock()
+                .unwrap()
+                .contains(&self.verified_key(user, password))
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let hash = match (self.users.get(user), self.dummy.as_ref()) {
+            (Some(hash), _) => hash,
+            (None, Some(dummy)) => {
+                let _ = bcrypt::verify(password, dummy);
+                return false;
+            }
+            (None, None) => return false,
+        };
+
+        if is_bcrypt(hash) {
+            let key = self.verified_key(user, password);
+            if self.verified.lock().unwrap().contains(&key) {
+                return true;
+            }
+            let ok = bcrypt::verify(password, hash).unwrap_or(false);
+            if ok {
+                let mut verified = self.verified.lock().unwrap();
+                if verified.len() >= MAX_VERIFIED {
+                    verified.clear();
+                }
+                verified.insert(key);
+            }
+            ok
+        } else if hash.starts_with("{SHA}") {
+            let digest = sha1::Sha1::from(password).digest().bytes();
+            constant_time_eq(base64::encode(&digest).as_bytes(), hash[5..].as_bytes())
+        } else if hash.starts_with('$') {
+            // Other crypt(3) schemes such as `$apr1$` are not supported.
+            false
+        } else {
+            let plain = hash.trim_start_matches("{PLAIN}");
+            constant_time_eq(password.as_bytes(), plain.as_bytes())
+        }
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    hash.starts_with("$2y$") || hash.starts_with("$2a$") || hash.starts_with("$2b$")
+}
+
+/// Decodes the credentials of a `Basic` authorization value.
+pub fn parse_basic(value: &str) -> Option<(String, String)> {
+    let mut split = value.trim().splitn(2, ' ');
+    let scheme = split.next()?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+
+    let decoded = base64::decode(split.next()?.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut split = decoded.splitn(2, ':');
+    let user = split.next()?.to_string();
+    let password = split.next()?.to_string();
+
+    Some((user, password))
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// `user:realm:HA1` entries read from an htdigest file.
+#[derive(Debug, Clone, Default)]
+pub struct Htdigest {
+    users: HashMap<(String, String), String>,
+}
+
+impl Htdigest {
+    pub fn load(path: &str) -> Result<Htdigest, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("reading htdigest {} failed: {:?}", path, e))?;
+
+        let users = text
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .filter_map(|l| {
+                let mut split = l.splitn(3, ':');
+                let user = split.next()?;
+                let realm = split.next()?;
+                let ha1 = split.next()?;
+                Some(((user.to_string(), realm.to_string()), ha1.to_lowercase()))
+            })
+            .collect();
+
+        Ok(Htdigest { users })
+    }
+
+    pub fn ha1(&self, user: &str, realm: &str) -> Option<&str> {
+        self.users
+            .get(&(user.to_string(), realm.to_string()))
+            .map(|s| s.as_str())
+    }
+}
+
+/// How long a Digest nonce stays valid before the client is told it is stale.
+const NONCE_LIFETIME: u64 = 300;
+
+/// `Proxy-Authorization` checking with the Basic and Digest (MD5, `qop=auth`) schemes.
+#[derive(Debug, Clone)]
+pub struct ProxyAuth {
+    realm: String,
+    basic: Option<Htpasswd>,
+    digest: Option<Htdigest>,
+    secret: String,
+    /// The highest `nc` accepted with each live nonce, and when the nonce was issued.
+    counts: Arc<Mutex<HashMap<String, (u64, u64)>>>,
+}
+
+impl ProxyAuth {
+    pub fn from_config(config: &crate::config::Config) -> Result<Option<ProxyAuth>, String> {
+        let basic = match config.proxy_auth_file.as_ref() {
+            Some(path) => Some(Htpasswd::load(path)?),
+            None => None,
+        };
+        let digest = match config.proxy_auth_digest_file.as_ref() {
+            Some(path) => Some(Htdigest::load(path)?),
+            None => None,
+        };
+        if basic.is_none() && digest.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(ProxyAuth {
+            realm: config.proxy_auth_realm.clone(),
+            basic,
+            digest,
+            secret: format!("{:x}{:x}", rand::random::<u64>(), rand::random::<u64>()),
+            counts: Arc::default(),
+        }))
+    }
+
+    /// Whether `authenticate` would run bcrypt for `req`.
+    pub fn is_slow(&self, req: &Request) -> bool {
+        req.header("Proxy-Authorization")
+            .and_then(parse_basic)
+            .map_or(false, |(user, password)| self.is_slow_password(&user, &password))
+    }
+
+    /// Whether `verify_password` would run bcrypt.
+    pub fn is_slow_password(&self, user: &str, password: &str) -> bool {
+        self.basic.as_ref().map_or(false, |h| h.is_slow(user, password))
+    }
+
+    /// Returns the user `req` authenticates as, or whether a Digest nonce was merely stale.
+    pub fn authenticate(&self, req: &Request) -> Result<String, bool> {
+        let authorization = req.header("Proxy-Authorization").ok_or(false)?.trim();
+        let scheme = authorization.split_whitespace().next().unwrap_or_default();
+
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let htpasswd = self.basic.as_ref().ok_or(false)?;
+            parse_basic(authorization)
+                .filter(|(user, password)| htpasswd.verify(user, password))
+                .map(|(user, _)| user)
+                .ok_or(false)
+        } else if scheme.eq_ignore_ascii_case("Digest") {
+            let htdigest = self.digest.as_ref().ok_or(false)?;
+            self.verify_digest(htdigest, req, &authorization[scheme.len()..])
+        } else {
+            Err(false)
+        }
+    }
+
+    /// Checks a password received in the clear, as SOCKS5 sends it, against either file.
+    pub fn verify_password(&self, user: &str, password: &str) -> bool {
+        if self.basic.as_ref().map_or(false, |h| h.verify(user, password)) {
+            return true;
+        }
+        match self.digest.as_ref().and_then(|h| h.ha1(user, &self.realm)) {
+            Some(ha1) => {
+                let expected = md5_hex(&format!("{}:{}:{}", user, self.realm, password));
+                constant_time_eq(expected.as_bytes(), ha1.as_bytes())
+            }
+            None => false,
+        }
+    }
+
+    fn verify_digest(
+        &self,
+        htdigest: &Htdigest,
+        req: &Request,
+        params: &str,
+    ) -> Result<String, bool> {
+        let params = parse_params(params);
+        let param = |name: &str| params.get(name).map(|s| s.as_str()).ok_or(false);
+
+        let user = param("username")?;
+        let nonce = param("nonce")?;
+        let uri = param("uri")?;
+        let response = param("response")?;
+        let nc = param("nc")?;
+        if param("realm")? != self.realm || !is_target(req, uri) {
+            return Err(false);
+        }
+        // Without `qop` there is no `nc` to tell a replayed header from a fresh one.
+        if params.get("qop").map(|s| s.as_str()) != Some("auth") {
+            return Err(false);
+        }
+        let ha1 = htdigest.ha1(user, &self.realm).ok_or(false)?;
+
+        let ha2 = md5_hex(&format!("{}:{}", req.method, uri));
+        let expected = md5_hex(&format!(
+            "{}:{}:{}:{}:auth:{}",
+            ha1,
+            nonce,
+            nc,
+            param("cnonce")?,
+            ha2
+        ));
+        if !constant_time_eq(expected.as_bytes(), response.to_lowercase().as_bytes()) {
+            return Err(false);
+        }
+
+        // The password was right; only an expired, forged or replayed nonce is left to
+        // reject.
+        let issued = self.nonce_issued(nonce).ok_or(false)?;
+        if now().saturating_sub(issued) > NONCE_LIFETIME {
+            return Err(true);
+        }
+        let nc = u64::from_str_radix(nc, 16).map_err(|_| false)?;
+        let mut counts = self.counts.lock().unwrap();
+        counts.retain(|_, (_, issued)| now().saturating_sub(*issued) <= NONCE_LIFETIME);
+        let last = counts.entry(nonce.to_string()).or_insert((0, issued));
+        if nc <= last.0 {
+            return Err(false);
+        }
+        last.0 = nc;
+        Ok(user.to_string())
+    }
+
+    fn nonce(&self, timestamp: u64) -> String {
+        let signature = md5_hex(&format!("{}:{}", timestamp, self.secret));
+        base64::encode(&format!("{}:{}", timestamp, signature))
+    }
+
+    /// When `nonce` was issued, if this proxy issued it.
+    fn nonce_issued(&self, nonce: &str) -> Option<u64> {
+        let decoded = String::from_utf8(base64::decode(nonce).ok()?).ok()?;
+        let timestamp = decoded.split(':').next()?.parse::<u64>().ok()?;
+        if self.nonce(timestamp) != nonce {
+            return None;
+        }
+        Some(timestamp)
+    }
+
+    /// `Proxy-Authenticate` values offering every configured scheme.
+    pub fn challenges(&self, stale: bool) -> Vec<String> {
+        let mut challenges = vec![];
+        if self.digest.is_some() {
+            challenges.push(format!(
+                "Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                self.realm,
+                self.nonce(now()),
+                if stale { ", stale=true" } else { "" }
+            ));
+        }
+        if self.basic.is_some() {
+            challenges.push(format!("Basic realm=\"{}\"", self.realm));
+        }
+        challenges
+    }
+}
+
+/// Whether the `uri` a Digest response covers is what `req` asks for.
+fn is_target(req: &Request, uri: &str) -> bool {
+    if req.method == "CONNECT" {
+        upstream::parse_authority(uri).ok() == Some((req.uri.host.clone(), req.uri.port))
+    } else {
+        parse_uri(uri, &req.uri.host).map_or(false, |uri| uri == req.uri)
+    }
+}
+
+/// Parses `key=value, key="quoted value"` pairs of an authorization header.
+fn parse_params(s: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = s.trim();
+
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => break,
+        };
+        let key = rest[..eq].trim().to_lowercase();
+        rest = rest[eq + 1..].trim_start();
+
+        let value = if rest.starts_with('"') {
+            let end = rest[1..].find('"').map(|i| i + 1).unwrap_or(rest.len());
+            let value = rest[1..end].to_string();
+            rest = rest.get(end + 1..).unwrap_or_default();
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+        params.insert(key, value);
+
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+    }
+
+    params
+}
+
+fn md5_hex(s: &str) -> String {
+    format!("{:x}", md5::compute(s))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REALM: &str = "proxylab";
+
+    fn proxy_auth() -> ProxyAuth {
+        let mut htpasswd = Htpasswd::default();
+        htpasswd.users.insert("bob".to_string(), "{PLAIN}hunter2".to_string());
+        let mut htdigest = Htdigest::default();
+        let ha1 = md5_hex(&format!("alice:{}:secret", REALM));
+        htdigest.users.insert(("alice".to_string(), REALM.to_string()), ha1);
+
+        ProxyAuth {
+            realm: REALM.to_string(),
+            basic: Some(htpasswd),
+            digest: Some(htdigest),
+            secret: "test".to_string(),
+            counts: Arc::default(),
+        }
+    }
+
+    fn request(method: &str, uri: &str, authorization: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            uri: parse_uri(uri, "").unwrap(),
+            version: "HTTP/1.1".to_string(),
+            headers: vec![format!("Proxy-Authorization: {}", authorization)],
+            body: vec![],
+        }
+    }
+
+    fn digest(method: &str, uri: &str, nonce: &str, nc: &str, password: &str) -> String {
+        let ha1 = md5_hex(&format!("alice:{}:{}", REALM, password));
+        let ha2 = md5_hex(&format!("{}:{}", method, uri));
+        let response = md5_hex(&format!("{}:{}:{}:abc:auth:{}", ha1, nonce, nc, ha2));
+        format!(
+            "Digest username=\"alice\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", qop=auth, \
+             nc={}, cnonce=\"abc\", response=\"{}\"",
+            REALM, nonce, uri, nc, response
+        )
+    }
+
+    #[test]
+    fn digest_accepts_increasing_nc() {
+        let auth = proxy_auth();
+        let nonce = auth.nonce(now());
+        let uri = "http://example.com/a";
+        let first = request("GET", uri, &digest("GET", uri, &nonce, "00000001", "secret"));
+        assert_eq!(auth.authenticate(&first), Ok("alice".to_string()));
+        let second = request("GET", uri, &digest("GET", uri, &nonce, "00000002", "secret"));
+        assert_eq!(auth.authenticate(&second), Ok("alice".to_string()));
+    }
+
+    #[test]
+    fn digest_refuses_replayed_nc() {
+        let auth = proxy_auth();
+        let nonce = auth.nonce(now());
+        let uri = "http://example.com/a";
+        let req = request("GET", uri, &digest("GET", uri, &nonce, "00000002", "secret"));
+        assert!(auth.authenticate(&req).is_ok());
+        assert_eq!(auth.authenticate(&req), Err(false));
+        let lower = request("GET", uri, &digest("GET", uri, &nonce, "00000001", "secret"));
+        assert_eq!(auth.authenticate(&lower), Err(false));
+    }
+
+    #[test]
+    fn digest_refuses_other_targets() {
+        let auth = proxy_auth();
+        let nonce = auth.nonce(now());
+        let signed = digest("GET", "http://example.com/a", &nonce, "00000001", "secret");
+        let req = request("GET", "http://example.com/admin", &signed);
+        assert_eq!(auth.authenticate(&req), Err(false));
+
+        let signed = digest("POST", "http://example.com/a", &nonce, "00000001", "secret");
+        let req = request("GET", "http://example.com/a", &signed);
+        assert_eq!(auth.authenticate(&req), Err(false));
+    }
+
+    #[test]
+    fn digest_covers_connect_authority() {
+        let auth = proxy_auth();
+        let nonce = auth.nonce(now());
+        let signed = digest("CONNECT", "example.com:443", &nonce, "00000001", "secret");
+        let mut req = request("CONNECT", "http://example.com/", &signed);
+        req.uri.port = 443;
+        assert_eq!(auth.authenticate(&req), Ok("alice".to_string()));
+    }
+
+    #[test]
+    fn digest_stale_and_forged_nonces() {
+        let auth = proxy_auth();
+        let uri = "http://example.com/a";
+        let old = auth.nonce(now() - NONCE_LIFETIME - 10);
+        let req = request("GET", uri, &digest("GET", uri, &old, "00000001", "secret"));
+        assert_eq!(auth.authenticate(&req), Err(true));
+
+        let forged = base64::encode(&format!("{}:{}", now(), md5_hex("forged")));
+        let req = request("GET", uri, &digest("GET", uri, &forged, "00000001", "secret"));
+        assert_eq!(auth.authenticate(&req), Err(false));
+
+        let nonce = auth.nonce(now());
+        let req = request("GET", uri, &digest("GET", uri, &nonce, "00000001", "wrong"));
+        assert_eq!(auth.authenticate(&req), Err(false));
+    }
+
+    #[test]
+    fn malformed_authorization() {
+        let auth = proxy_auth();
+        let nonce = auth.nonce(now());
+        let uri = "http://example.com/a";
+        let without_qop = digest("GET", uri, &nonce, "00000001", "secret");
+        let without_qop = without_qop.replace("qop=auth, ", "");
+        let bad_nc = digest("GET", uri, &nonce, "zz", "secret");
+        for authorization in &[
+            "",
+            "Digest",
+            "Digest username=",
+            "Digest username=\"alice\", realm=\"proxylab\"",
+            without_qop.as_str(),
+            bad_nc.as_str(),
+            "Basic",
+            "Basic !!!",
+            "Basic Ym9i",
+            "Bearer token",
+        ] {
+            let req = request("GET", uri, authorization);
+            assert_eq!(auth.authenticate(&req), Err(false), "{}", authorization);
+        }
+
+        let mut req = request("GET", uri, "");
+        req.headers.clear();
+        assert_eq!(auth.authenticate(&req), Err(false));
+    }
+
+    #[test]
+    fn basic() {
+        let auth = proxy_auth();
+        let uri = "http://example.com/a";
+        let good = format!("basic {}", base64::encode("bob:hunter2"));
+        assert_eq!(auth.authenticate(&request("GET", uri, &good)), Ok("bob".to_string()));
+        let bad = format!("Basic {}", base64::encode("bob:hunter3"));
+        assert_eq!(auth.authenticate(&request("GET", uri, &bad)), Err(false));
+        assert_eq!(
+            parse_basic(&format!("Basic {}", base64::encode("a:b:c"))),
+            Some(("a".to_string(), "b:c".to_string()))
+        );
+    }
+
+    #[test]
+    fn htpasswd() {
+        let path = std::env::temp_dir().join(format!("proxylab-htpasswd-{}", std::process::id()));
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let text = format!("carol:{}\ndave:{{SHA}}{}\n", hash, "5en6G6MezRroT3XKqkdPOmY/BfQ=");
+        fs::write(&path, text).unwrap();
+        let htpasswd = Htpasswd::load(path.to_str().unwrap());
+        let _ = fs::remove_file(&path);
+        let htpasswd = htpasswd.unwrap();
+
+        assert!(htpasswd.is_slow("carol", "secret"));
+        assert!(htpasswd.verify("carol", "secret"));
+        assert!(!htpasswd.is_slow("carol", "secret"));
+        assert!(!htpasswd.verify("carol", "wrong"));
+        assert!(htpasswd.verify("dave", "secret"));
+        assert!(!htpasswd.verify("dave", "wrong"));
+        // Unknown users go through bcrypt too, so they take as long to refuse.
+        assert!(htpasswd.is_slow("mallory", "secret"));
+        assert!(!htpasswd.verify("mallory", "secret"));
+    }
+
+    #[test]
+    fn params() {
+        let params = parse_params("a=1, b=\"x, y\",c=\"\", d");
+        assert_eq!(params.get("a").map(|s| s.as_str()), Some("1"));
+        assert_eq!(params.get("b").map(|s| s.as_str()), Some("x, y"));
+        assert_eq!(params.get("c").map(|s| s.as_str()), Some(""));
+        assert_eq!(params.get("d"), None);
+    }
+}
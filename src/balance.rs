@@ -0,0 +1,312 @@
+use crate::{
+    config::Config,
+    health::{ActiveCheck, Health, OutlierDetection},
+    upstream, Request,
+};
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// Points each backend gets on the hash ring per unit of weight.
+const RING_POINTS: u32 = 100;
+
+/// What a consistent-hash pool hashes to pick a backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashKey {
+    ClientIp,
+    /// A request header, falling back to the client IP when it is missing.
+    Header(String),
+    /// A cookie, falling back to the client IP when it is missing.
+    Cookie(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    Weighted,
+    LeastConnections,
+    Hash(HashKey),
+}
+
+impl Strategy {
+    fn parse(s: &str) -> Result<Strategy, String> {
+        let mut split = s.splitn(2, ':');
+        match (split.next().unwrap_or_default(), split.next()) {
+            ("round_robin", None) => Ok(Strategy::RoundRobin),
+            ("weighted", None) => Ok(Strategy::Weighted),
+            ("least_conn", None) => Ok(Strategy::LeastConnections),
+            ("hash", Some("ip")) => Ok(Strategy::Hash(HashKey::ClientIp)),
+            ("hash", Some(key)) if key.starts_with("header=") => {
+                Ok(Strategy::Hash(HashKey::Header(key["header=".len()..].to_string())))
+            }
+            ("hash", Some(key)) if key.starts_with("cookie=") => {
+                Ok(Strategy::Hash(HashKey::Cookie(key["cookie=".len()..].to_string())))
+            }
+            _ => Err(format!("unknown balancing strategy `{}`", s)),
+        }
+    }
+}
+
+/// A `pool <name> <round_robin|weighted|least_conn|hash:ip|hash:header=H|hash:cookie=C>
+/// <host:port[=weight]>...` directive.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    pub strategy: Strategy,
+    pub backends: Vec<(String, u16, u32)>,
+}
+
+impl PoolConfig {
+    pub fn parse(args: &[&str]) -> Result<PoolConfig, String> {
+        if args.len() < 3 {
+            return Err("expected a name, a strategy and at least one backend".to_string());
+        }
+
+        let backends = args[2..]
+            .iter()
+            .map(|backend| {
+                let mut split = backend.splitn(2, '=');
+                let (host, port) = upstream::parse_authority(split.next().unwrap_or_default())?;
+                let weight = match split.next() {
+                    Some(w) => w
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|w| *w > 0)
+                        .ok_or_else(|| format!("invalid weight in `{}`", backend))?,
+                    None => 1,
+                };
+                Ok((host, port, weight))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(PoolConfig {
+            name: args[0].to_string(),
+            strategy: Strategy::parse(args[1])?,
+            backends,
+        })
+    }
+}
+
+/// One pool member with its count of requests in flight.
+#[derive(Debug)]
+pub struct Backend {
+    pub host: String,
+    pub port: u16,
+    pub weight: u32,
+    active: AtomicUsize,
+    pub health: Health,
+}
+
+impl Backend {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn authority(&self) -> String {
+        upstream::format_authority(&self.host, self.port)
+    }
+}
+
+/// Counts a request against its backend until dropped.
+#[derive(Debug)]
+pub struct Lease {
+    pub backend: Arc<Backend>,
+}
+
+impl Lease {
+    fn new(backend: Arc<Backend>) -> Lease {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Lease { backend }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    strategy: Strategy,
+    pub backends: Vec<Arc<Backend>>,
+    next: AtomicUsize,
+    pub active: Option<ActiveCheck>,
+    outlier: Option<OutlierDetection>,
+    /// Sorted `(point, backend index)` pairs for consistent hashing.
+    ring: Vec<(u64, usize)>,
+}
+
+impl Pool {
+    pub fn new(
+        config: &PoolConfig,
+        active: Option<ActiveCheck>,
+        outlier: Option<OutlierDetection>,
+    ) -> Pool {
+        let backends = config
+            .backends
+            .iter()
+            .map(|(host, port, weight)| {
+                Arc::new(Backend {
+                    host: host.clone(),
+                    port: *port,
+                    weight: *weight,
+                    active: AtomicUsize::new(0),
+                    health: Health::default(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut ring = vec![];
+        if let Strategy::Hash(_) = config.strategy {
+            for (i, backend) in backends.iter().enumerate() {
+                for point in 0..backend.weight * RING_POINTS {
+                    ring.push((hash(&format!("{}#{}", backend.authority(), point)), i));
+                }
+            }
+            ring.sort();
+        }
+
+        Pool {
+            name: config.name.clone(),
+            strategy: config.strategy.clone(),
+            backends,
+            next: AtomicUsize::new(0),
+            active,
+            outlier,
+            ring,
+        }
+    }
+
+    /// Chooses a healthy backend for `req` and counts the request against it.
+    ///
+    /// Members in `avoid`, given as `host:port`, are only picked when no other is up.
+    pub fn pick(&self, client: IpAddr, req: &Request, avoid: &[String]) -> Option<Lease> {
+        let up = (0..self.backends.len())
+            .filter(|i| self.is_up(*i))
+            .collect::<Vec<_>>();
+        let fresh = up
+            .iter()
+            .cloned()
+            .filter(|i| !avoid.contains(&self.backends[*i].authority()))
+            .collect::<Vec<_>>();
+        let up = if fresh.is_empty() { up } else { fresh };
+        if up.is_empty() {
+            return None;
+        }
+
+        let i = match &self.strategy {
+            Strategy::RoundRobin => up[self.next.fetch_add(1, Ordering::Relaxed) % up.len()],
+            Strategy::Weighted => {
+                let weight = |i: &usize| self.backends[*i].weight as usize;
+                let total = up.iter().map(weight).sum::<usize>();
+                let mut n = self.next.fetch_add(1, Ordering::Relaxed) % total;
+                *up.iter()
+                    .find(|i| {
+                        if n < weight(i) {
+                            true
+                        } else {
+                            n -= weight(i);
+                            false
+                        }
+                    })
+                    .unwrap_or(&up[0])
+            }
+            // Fewest requests in flight relative to weight, compared without dividing.
+            Strategy::LeastConnections => *up
+                .iter()
+                .min_by(|a, b| {
+                    let (a, b) = (&self.backends[**a], &self.backends[**b]);
+                    (a.active() * b.weight as usize).cmp(&(b.active() * a.weight as usize))
+                })
+                .unwrap_or(&up[0]),
+            // Walks the ring past members that are down, so only their keys move.
+            Strategy::Hash(key) => {
+                let point = hash(&hash_key(key, client, req));
+                let start = match self.ring.binary_search(&(point, 0)) {
+                    Ok(at) | Err(at) => at,
+                };
+                (0..self.ring.len())
+                    .map(|n| self.ring[(start + n) % self.ring.len()].1)
+                    .find(|i| up.contains(i))
+                    .unwrap_or(up[0])
+            }
+        };
+
+        Some(Lease::new(self.backends[i].clone()))
+    }
+
+    fn is_up(&self, i: usize) -> bool {
+        let backend = &self.backends[i];
+        backend.health.is_up(&self.backend_name(backend))
+    }
+
+    /// How a member is named in health logs: `pool/host:port`.
+    pub fn backend_name(&self, backend: &Backend) -> String {
+        format!("{}/{}", self.name, backend.authority())
+    }
+
+    /// Feeds the outcome of a proxied request to outlier detection, if configured.
+    pub fn report(&self, backend: &Backend, result: Result<(), String>) {
+        if let Some(detection) = self.outlier.as_ref() {
+            backend
+                .health
+                .observe(&self.backend_name(backend), detection, result);
+        }
+    }
+}
+
+fn hash_key(key: &HashKey, client: IpAddr, req: &Request) -> String {
+    let value = match key {
+        HashKey::ClientIp => None,
+        HashKey::Header(name) => req.header(name).map(|v| v.to_string()),
+        HashKey::Cookie(name) => req.header("Cookie").and_then(|cookies| {
+            cookies.split(';').find_map(|cookie| {
+                let mut split = cookie.trim().splitn(2, '=');
+                if split.next()? == name {
+                    split.next().map(|v| v.to_string())
+                } else {
+                    None
+                }
+            })
+        }),
+    };
+    value.unwrap_or_else(|| client.to_string())
+}
+
+fn hash(s: &str) -> u64 {
+    let digest = md5::compute(s);
+    digest[..8]
+        .iter()
+        .fold(0, |acc, byte| (acc << 8) | u64::from(*byte))
+}
+
+/// The runtime state of every configured pool.
+#[derive(Debug, Default)]
+pub struct Pools {
+    pub pools: Vec<Pool>,
+}
+
+impl Pools {
+    pub fn new(config: &Config) -> Pools {
+        let pools = config
+            .pools
+            .iter()
+            .map(|pool| {
+                let active = config.health_checks.iter().find(|c| c.pool == pool.name);
+                let outlier = config.outlier_detection.iter().find(|d| d.pool == pool.name);
+                Pool::new(pool, active.cloned(), outlier.cloned())
+            })
+            .collect();
+        Pools { pools }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Pool> {
+        self.pools.iter().find(|p| p.name == name)
+    }
+}
@@ -0,0 +1,329 @@
+use crate::acl::normalize_host;
+use aho_corasick::{AcAutomaton, Automaton};
+use regex::Regex;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `0.0.0.0 ads.example.com` lines, as in `/etc/hosts`.
+    Hosts,
+    /// Adblock filter syntax: `||domain^`, `|http://prefix`, `/path*fragment`, `@@` exceptions.
+    Adblock,
+}
+
+/// A `blocklist <name> <hosts|adblock> <path>` directive.
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub name: String,
+    pub format: Format,
+    pub path: String,
+}
+
+impl Source {
+    pub fn parse(args: &[&str]) -> Result<Source, String> {
+        if args.len() != 3 {
+            return Err("expected a name, a format and a path".to_string());
+        }
+        let format = match args[1] {
+            "hosts" => Format::Hosts,
+            "adblock" => Format::Adblock,
+            s => return Err(format!("unknown blocklist format `{}`", s)),
+        };
+
+        Ok(Source {
+            name: args[0].to_string(),
+            format,
+            path: args[2].to_string(),
+        })
+    }
+}
+
+/// URL patterns indexed by a literal keyword, so only the rules whose keyword occurs in
+/// the URL have their regex evaluated.
+#[derive(Debug)]
+struct UrlRules {
+    keywords: AcAutomaton<String>,
+    by_keyword: Vec<Vec<usize>>,
+    unindexed: Vec<usize>,
+    regexes: Vec<Regex>,
+    /// Patterns left out because their regex did not compile.
+    invalid: usize,
+}
+
+impl UrlRules {
+    fn new(patterns: Vec<String>) -> UrlRules {
+        let mut keyword_index = HashMap::new();
+        let mut keywords = vec![];
+        let mut by_keyword: Vec<Vec<usize>> = vec![];
+        let mut unindexed = vec![];
+        let mut regexes = vec![];
+        let mut invalid = 0;
+
+        for pattern in patterns {
+            let regex = match Regex::new(&adblock_regex(&pattern)) {
+                Ok(regex) => regex,
+                Err(_) => {
+                    invalid += 1;
+                    continue;
+                }
+            };
+            let i = regexes.len();
+            regexes.push(regex);
+
+            match keyword(&pattern) {
+                Some(keyword) => {
+                    let k = *keyword_index.entry(keyword.clone()).or_insert_with(|| {
+                        keywords.push(keyword);
+                        by_keyword.push(vec![]);
+                        by_keyword.len() - 1
+                    });
+                    by_keyword[k].push(i);
+                }
+                None => unindexed.push(i),
+            }
+        }
+
+        UrlRules {
+            keywords: AcAutomaton::new(keywords),
+            by_keyword,
+            unindexed,
+            regexes,
+            invalid,
+        }
+    }
+
+    fn is_match(&self, url: &str) -> bool {
+        let url = url.to_lowercase();
+        self.keywords
+            .find_overlapping(&url)
+            .flat_map(|m| self.by_keyword[m.pati].iter())
+            .chain(self.unindexed.iter())
+            .any(|i| self.regexes[*i].is_match(&url))
+    }
+}
+
+/// Returns the longest literal run of an adblock pattern, used to index it.
+fn keyword(pattern: &str) -> Option<String> {
+    pattern
+        .split(|c| c == '*' || c == '^' || c == '|')
+        .max_by_key(|s| s.len())
+        .filter(|s| s.len() >= 3)
+        .map(|s| s.to_lowercase())
+}
+
+/// Translates an adblock URL pattern into a regex.
+fn adblock_regex(pattern: &str) -> String {
+    let mut regex = String::new();
+    let mut rest = pattern;
+
+    if rest.starts_with("||") {
+        regex.push_str(r"^[a-z][a-z0-9+.-]*://([^/?#]*\.)?");
+        rest = &rest[2..];
+    } else if rest.starts_with('|') {
+        regex.push('^');
+        rest = &rest[1..];
+    }
+    let anchored_end = rest.ends_with('|');
+    if anchored_end {
+        rest = &rest[..rest.len() - 1];
+    }
+
+    for c in rest.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '^' => regex.push_str(r"(?:[^a-z0-9_.%-]|$)"),
+            c => regex.push_str(&regex::escape(&c.to_lowercase().to_string())),
+        }
+    }
+    if anchored_end {
+        regex.push('$');
+    }
+
+    regex
+}
+
+/// One loaded list with its hit counter.
+#[derive(Debug)]
+pub struct Blocklist {
+    pub source: Source,
+    domains: HashSet<String>,
+    allowed_domains: HashSet<String>,
+    urls: UrlRules,
+    exceptions: UrlRules,
+    hits: AtomicUsize,
+}
+
+impl Blocklist {
+    pub fn load(source: Source) -> Result<Blocklist, String> {
+        let text = fs::read_to_string(&source.path)
+            .map_err(|e| format!("reading blocklist {} failed: {:?}", source.path, e))?;
+
+        let mut domains = HashSet::new();
+        let mut allowed_domains = HashSet::new();
+        let mut urls = vec![];
+        let mut exceptions = vec![];
+
+        for line in text.lines().map(|l| l.trim()) {
+            match source.format {
+                Format::Hosts => {
+                    let line = line.split('#').next().unwrap_or_default();
+                    let mut words = line.split_whitespace();
+                    let _ip = words.next();
+                    domains.extend(
+                        words
+                            .filter(|d| *d != "localhost")
+                            .map(normalize_host),
+                    );
+                }
+                Format::Adblock => {
+                    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                        continue;
+                    }
+                    // Element hiding rules only make sense in a browser.
+                    if line.contains("##") || line.contains("#@#") {
+                        continue;
+                    }
+                    let (is_exception, rule) = if line.starts_with("@@") {
+                        (true, &line[2..])
+                    } else {
+                        (false, line)
+                    };
+                    // Request type and domain options are not supported; apply the
+                    // pattern unconditionally.
+                    let rule = rule.split('$').next().unwrap_or_default();
+
+                    if let Some(domain) = plain_domain(rule) {
+                        if is_exception {
+                            allowed_domains.insert(domain);
+                        } else {
+                            domains.insert(domain);
+                        }
+                    } else if !rule.is_empty() {
+                        if is_exception {
+                            exceptions.push(rule.to_string());
+                        } else {
+                            urls.push(rule.to_string());
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Blocklist {
+            source,
+            domains,
+            allowed_domains,
+            urls: UrlRules::new(urls),
+            exceptions: UrlRules::new(exceptions),
+            hits: AtomicUsize::new(0),
+        })
+    }
+
+    /// Checks `host` and every parent domain, so a listed domain covers its subdomains.
+    fn has_domain(set: &HashSet<String>, host: &str) -> bool {
+        let host = host.to_lowercase();
+        let mut suffix = host.as_str();
+        loop {
+            if set.contains(suffix) {
+                return true;
+            }
+            match suffix.find('.') {
+                Some(i) => suffix = &suffix[i + 1..],
+                None => return false,
+            }
+        }
+    }
+
+    pub fn is_blocked(&self, host: &str, url: &str) -> bool {
+        let host = normalize_host(host);
+        if Blocklist::has_domain(&self.allowed_domains, &host) || self.exceptions.is_match(url) {
+            return false;
+        }
+        let blocked = match self.source.format {
+            Format::Hosts => self.domains.contains(&host),
+            Format::Adblock => {
+                Blocklist::has_domain(&self.domains, &host) || self.urls.is_match(url)
+            }
+        };
+        if blocked {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        blocked
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
+
+/// `||example.com^` with nothing else is a plain domain rule.
+fn plain_domain(rule: &str) -> Option<String> {
+    if !rule.starts_with("||") {
+        return None;
+    }
+    let domain = rule[2..].trim_end_matches('^');
+    if !domain.is_empty()
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    {
+        Some(domain.to_lowercase())
+    } else {
+        None
+    }
+}
+
+/// Every configured list, reloaded as a whole.
+#[derive(Debug, Default)]
+pub struct Blocklists {
+    pub lists: Vec<Blocklist>,
+}
+
+impl Blocklists {
+    pub fn load(sources: &[Source]) -> Result<Blocklists, String> {
+        let lists = sources
+            .iter()
+            .map(|s| Blocklist::load(s.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Blocklists { lists })
+    }
+
+    /// Carries hit counters over from the lists this one replaces.
+    pub fn keep_hits(&self, old: &Blocklists) {
+        for list in self.lists.iter() {
+            if let Some(old) = old
+                .lists
+                .iter()
+                .find(|l| l.source.name == list.source.name)
+            {
+                list.hits.store(old.hits(), Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns the name of the first list blocking the request.
+    pub fn check(&self, host: &str, url: &str) -> Option<&str> {
+        self.lists
+            .iter()
+            .find(|l| l.is_blocked(host, url))
+            .map(|l| l.source.name.as_str())
+    }
+
+    pub fn log(&self) {
+        for list in self.lists.iter() {
+            println!(
+                "blocklist {}: {} domains, {} patterns, {} invalid patterns skipped, {} hits",
+                list.source.name,
+                list.domains.len(),
+                list.urls.regexes.len(),
+                list.urls.invalid + list.exceptions.invalid,
+                list.hits()
+            );
+        }
+        println!();
+    }
+}
@@ -0,0 +1,415 @@
+use crate::*;
+use futures::{
+    channel::{mpsc, oneshot},
+    executor::block_on,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom},
+    net::SocketAddr,
+    path::PathBuf,
+    os::unix::process::CommandExt,
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::RecvTimeoutError,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Largest piece of script output held in memory at once.
+const OUTPUT_CHUNK_SIZE: usize = 64 * 1024;
+/// Pieces of output that may wait for a slow client before the script is held up.
+const OUTPUT_BUFFERED: usize = 4;
+/// Longest head a script may write before its body.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// A CGI/1.1 script invocation.
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    pub script: PathBuf,
+    pub script_name: String,
+    pub path_info: String,
+    pub remote_addr: SocketAddr,
+    pub server_port: u16,
+    pub timeout: Duration,
+}
+
+impl Cgi {
+    /// Splits `path` into the script under `prefix` and the trailing path info.
+    pub fn resolve(
+        root: &str,
+        prefix: &str,
+        path: &str,
+        remote_addr: SocketAddr,
+        server_port: u16,
+        timeout: Duration,
+    ) -> Option<Cgi> {
+        let path = path.split('?').next().unwrap_or_default();
+        if !path.starts_with(prefix) || !path[prefix.len()..].starts_with('/') {
+            return None;
+        }
+        let rest = &path[prefix.len()..];
+
+        let mut split = rest[1..].splitn(2, '/');
+        let script = split.next().filter(|s| !s.is_empty() && *s != "..")?;
+        let path_info = split.next().map(|s| format!("/{}", s)).unwrap_or_default();
+
+        Some(Cgi {
+            script: PathBuf::from(format!("{}{}/{}", root, prefix, script)),
+            script_name: format!("{}/{}", prefix, script),
+            path_info,
+            remote_addr,
+            server_port,
+            timeout,
+        })
+    }
+
+    fn env(&self, req: &Request, body_len: u64) -> Vec<(String, String)> {
+        let query = req.uri.path.splitn(2, '?').nth(1).unwrap_or_default();
+
+        let mut env = vec![
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("SERVER_SOFTWARE", "proxylab".to_string()),
+            ("SERVER_NAME", req.uri.host.clone()),
+            ("SERVER_PORT", self.server_port.to_string()),
+            ("SERVER_PROTOCOL", req.version.clone()),
+            ("REQUEST_METHOD", req.method.clone()),
+            ("SCRIPT_NAME", self.script_name.clone()),
+            ("PATH_INFO", self.path_info.clone()),
+            ("QUERY_STRING", query.to_string()),
+            ("REMOTE_ADDR", self.remote_addr.ip().to_string()),
+            ("REMOTE_PORT", self.remote_addr.port().to_string()),
+            ("PATH", "/usr/local/bin:/usr/bin:/bin".to_string()),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect::<Vec<_>>();
+
+        if body_len > 0 {
+            env.push(("CONTENT_LENGTH".to_string(), body_len.to_string()));
+        }
+        if let Some(content_type) = req.header("Content-Type") {
+            env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+        }
+
+        for h in req.headers.iter() {
+            let mut split = h.splitn(2, ':');
+            let name = split.next().unwrap_or_default().trim();
+            let value = split.next().unwrap_or_default().trim();
+            if name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Content-Type")
+                || name.eq_ignore_ascii_case("Authorization")
+                // `HTTP_PROXY` would point the script's HTTP clients at the client's proxy.
+                || name.eq_ignore_ascii_case("Proxy")
+            {
+                continue;
+            }
+            env.push((
+                format!("HTTP_{}", name.to_uppercase().replace('-', "_")),
+                value.to_string(),
+            ));
+        }
+
+        env
+    }
+
+    /// Starts the script with the spooled request body on stdin and waits for the head of
+    /// its response; the body follows from the returned `Output`.
+    pub async fn start(self, req: Request, body: Spool) -> Result<(Response, Output), HttpError> {
+        let env = self.env(&req, body.len);
+        let cgi = self;
+
+        let (tx, rx) = mpsc::channel(OUTPUT_BUFFERED);
+        let (outcome_tx, outcome) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = outcome_tx.send(execute(&cgi, env, body.file, tx));
+        });
+
+        let mut output = Output {
+            rx,
+            outcome,
+            pending: vec![],
+        };
+        let mut head = vec![];
+        let (head_len, body_start) = loop {
+            if let Some(end) = head_end(&head) {
+                break end;
+            }
+            if head.len() > MAX_HEAD_SIZE {
+                return Err(HttpError::BadGateway("script output head is too long".to_string()));
+            }
+            match await!(output.rx.next()) {
+                Some(mut piece) => head.append(&mut piece),
+                None => {
+                    await!(output.wait())?;
+                    return Err(HttpError::BadGateway(
+                        "script output has no header end".to_string(),
+                    ));
+                }
+            }
+        };
+
+        output.pending = head.split_off(body_start);
+        let resp = parse_head(req.version, &head[..head_len])?;
+        Ok((resp, output))
+    }
+}
+
+/// A request body written out to an unlinked temporary file, which becomes the script's
+/// stdin; CGI needs the whole length before the script starts.
+#[derive(Debug)]
+pub struct Spool {
+    file: File,
+    pub len: u64,
+}
+
+/// Copies the body framed by `framing` from `reader` into a `Spool`, a piece at a time.
+pub async fn spool<R: AsyncRead + BufRead>(
+    reader: R,
+    framing: Framing,
+    limit: u64,
+) -> Result<(R, Spool), HttpError> {
+    static SPOOLS: AtomicUsize = AtomicUsize::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "proxylab-cgi-{}-{}",
+        std::process::id(),
+        SPOOLS.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .map_err(|e| HttpError::Internal(format!("{}: {:?}", path.display(), e)))?;
+    let _ = fs::remove_file(&path);
+
+    let file = tokio::fs::File::from_std(file);
+    let (reader, file, len) = await!(copy_body(reader, file, framing, limit, false))?;
+    let mut file = file.into_std();
+    file.seek(SeekFrom::Start(0))
+        .map_err(|e| HttpError::Internal(format!("rewinding request body failed: {:?}", e)))?;
+
+    Ok((reader, Spool { file, len }))
+}
+
+/// The rest of a running script's output.
+pub struct Output {
+    rx: mpsc::Receiver<Vec<u8>>,
+    outcome: oneshot::Receiver<Result<(), HttpError>>,
+    /// Body bytes that came along with the head.
+    pending: Vec<u8>,
+}
+
+impl Output {
+    /// Waits for the script to end, with the outcome of running it.
+    async fn wait(self) -> Result<(), HttpError> {
+        let outcome = await!(self.outcome);
+        outcome.unwrap_or_else(|_| Err(HttpError::Internal("script runner panicked".to_string())))
+    }
+
+    /// Copies the response body to `writer` as the script writes it, or drops it when
+    /// `discard` is set, and returns how many bytes the body had.
+    pub async fn send<W: AsyncWrite>(self, writer: W, discard: bool) -> Result<u64, io::Error> {
+        let mut output = self;
+        let mut writer = writer;
+        let mut piece = std::mem::replace(&mut output.pending, vec![]);
+        let mut size = 0;
+
+        loop {
+            size += piece.len() as u64;
+            if !discard && !piece.is_empty() {
+                let (w, _) = await!(io::write_all(writer, piece).compat())?;
+                writer = w;
+            }
+            match await!(output.rx.next()) {
+                Some(next) => piece = next,
+                None => break,
+            }
+        }
+
+        // The head is out, so a failure now can only be logged.
+        if let Err(e) = await!(output.wait()) {
+            println!("cgi: {:?}\n", e);
+        }
+        Ok(size)
+    }
+}
+
+fn execute(
+    cgi: &Cgi,
+    env: Vec<(String, String)>,
+    stdin: File,
+    output: mpsc::Sender<Vec<u8>>,
+) -> Result<(), HttpError> {
+    let script = cgi
+        .script
+        .canonicalize()
+        .map_err(|e| HttpError::NotFound(format!("{}: {:?}", cgi.script.display(), e)))?;
+
+    let mut command = Command::new(&script);
+    command
+        .env_clear()
+        .envs(env)
+        .current_dir(script.parent().unwrap_or_else(|| "/".as_ref()))
+        .stdin(Stdio::from(stdin))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    // A process group of its own lets the deadline take down whatever the script started.
+    unsafe {
+        command.before_exec(|| {
+            libc::setpgid(0, 0);
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().map_err(|e| {
+        HttpError::Internal(format!("{}: spawn failed: {:?}", cgi.script.display(), e))
+    })?;
+    let group = child.id() as libc::pid_t;
+    let timed_out = || {
+        unsafe { libc::kill(-group, libc::SIGKILL) };
+        HttpError::BadGateway(format!(
+            "{}: timed out after {:?}",
+            cgi.script.display(),
+            cgi.timeout
+        ))
+    };
+
+    // Pass stdout on from its own thread so the deadline below holds however the script
+    // writes, and a slow client only ever has a few pieces waiting for it. The thread
+    // drops `drained` when it ends.
+    let mut stdout = child.stdout.take().unwrap();
+    let (drained, drained_rx) = std::sync::mpsc::channel::<()>();
+    thread::spawn(move || {
+        let _drained = drained;
+        let mut output = output;
+        loop {
+            let mut buf = vec![0; OUTPUT_CHUNK_SIZE];
+            match stdout.read(&mut buf) {
+                Ok(0) => return,
+                Ok(n) => {
+                    buf.truncate(n);
+                    if block_on(output.send(buf)).is_err() {
+                        return;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return,
+            }
+        }
+    });
+
+    // The script is reaped on a thread of its own too, so the deadline is a timed wait for
+    // its exit; after a kill that thread reaps it all the same.
+    let deadline = Instant::now() + cgi.timeout;
+    let (exited, exited_rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let _ = exited.send(child.wait());
+    });
+    let status = match exited_rx.recv_timeout(cgi.timeout) {
+        Ok(status) => {
+            status.map_err(|e| HttpError::Internal(format!("waiting script failed: {:?}", e)))?
+        }
+        Err(RecvTimeoutError::Timeout) => return Err(timed_out()),
+        Err(RecvTimeoutError::Disconnected) => {
+            return Err(HttpError::Internal("waiting script failed".to_string()))
+        }
+    };
+
+    // Something the script left running may still hold its stdout open.
+    let now = Instant::now();
+    let left = if deadline > now {
+        deadline - now
+    } else {
+        Duration::from_millis(0)
+    };
+    if let Err(RecvTimeoutError::Timeout) = drained_rx.recv_timeout(left) {
+        return Err(timed_out());
+    }
+
+    if !status.success() {
+        return Err(HttpError::BadGateway(format!(
+            "{}: exited with {}",
+            cgi.script.display(),
+            status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Finds the blank line ending the head of CGI output, as the head's length and where the
+/// body starts.
+fn head_end(output: &[u8]) -> Option<(usize, usize)> {
+    let crlf = output
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| (i, i + 4));
+    let lf = output
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map(|i| (i, i + 2));
+    match (crlf, lf) {
+        (Some(crlf), Some(lf)) => Some(crlf.min(lf)),
+        (crlf, lf) => crlf.or(lf),
+    }
+}
+
+/// Converts the head of CGI output into a response, whose body is then delimited by
+/// closing the connection.
+fn parse_head(version: String, head: &[u8]) -> Result<Response, HttpError> {
+    let head = String::from_utf8_lossy(head).to_string();
+
+    let mut status = None;
+    let mut location = None;
+    let mut has_content_type = false;
+    let mut headers = vec![];
+    for line in head.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        let mut split = line.splitn(2, ':');
+        let name = split.next().unwrap_or_default().trim();
+        let value = split
+            .next()
+            .ok_or_else(|| HttpError::BadGateway(format!("malformed script header `{}`", line)))?
+            .trim();
+
+        if name.eq_ignore_ascii_case("Status") {
+            let mut split = value.splitn(2, ' ');
+            let code = split
+                .next()
+                .and_then(|s| s.parse::<u16>().ok())
+                .ok_or_else(|| HttpError::BadGateway(format!("malformed status `{}`", value)))?;
+            let reason = split.next().unwrap_or_default().to_string();
+            status = Some((code, reason));
+            continue;
+        } else if name.eq_ignore_ascii_case("Location") {
+            location = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("Content-Type") {
+            has_content_type = true;
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            continue;
+        }
+        headers.push(format!("{}: {}", name, value));
+    }
+
+    if !has_content_type && location.is_none() {
+        return Err(HttpError::BadGateway(
+            "script sent neither Content-Type nor Location".to_string(),
+        ));
+    }
+
+    let (status, reason) = match status {
+        Some(status) => status,
+        None if location.is_some() => (302, "Found".to_string()),
+        None => (200, "OK".to_string()),
+    };
+
+    Ok(Response {
+        version,
+        status,
+        reason,
+        headers,
+        content: vec![],
+    })
+}
@@ -0,0 +1,347 @@
+use crate::{
+    acl::{self, Acl},
+    balance,
+    blocklist,
+    dns,
+    errors::ErrorFormat,
+    fault,
+    har,
+    health,
+    limit,
+    mirror,
+    replay,
+    retry,
+    reverse,
+    throttle,
+    upstream,
+    vhost::VirtualHost,
+};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub mime_types: Option<String>,
+    pub mime_sniff: bool,
+    pub default_type: String,
+    pub charset: Option<String>,
+    pub cgi_bin: Option<String>,
+    pub cgi_timeout: Duration,
+    pub max_body_size: u64,
+    pub gzip: bool,
+    pub gzip_static: bool,
+    pub gzip_min_length: u64,
+    pub gzip_level: u32,
+    pub error_pages: Vec<(u16, String)>,
+    pub error_format: ErrorFormat,
+    pub proxy_intercept_errors: bool,
+    pub default_root: String,
+    pub vhosts: Vec<VirtualHost>,
+    pub writable: Vec<String>,
+    pub auth_file: Option<String>,
+    pub auth_realm: String,
+    pub proxy_auth_file: Option<String>,
+    pub proxy_auth_digest_file: Option<String>,
+    pub proxy_auth_realm: String,
+    pub acl: Acl,
+    pub blocklists: Vec<blocklist::Source>,
+    pub parent_proxies: Vec<upstream::Route>,
+    pub socks_port: Option<u16>,
+    pub routes: Vec<reverse::Route>,
+    pub pools: Vec<balance::PoolConfig>,
+    pub health_checks: Vec<health::ActiveCheck>,
+    pub outlier_detection: Vec<health::OutlierDetection>,
+    /// Where the admin endpoint listens; loopback unless `admin_port` names an address.
+    pub admin_addr: Option<SocketAddr>,
+    pub retry: retry::Policy,
+    pub connect_attempt_delay: Option<Duration>,
+    pub dns: dns::Settings,
+    pub limits: limit::Settings,
+    pub throttles: Vec<throttle::Rule>,
+    pub faults: Vec<fault::Rule>,
+    pub har: har::Settings,
+    pub replay: replay::Settings,
+    pub mirrors: Vec<mirror::Rule>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            mime_types: None,
+            mime_sniff: false,
+            default_type: "text/plain".to_string(),
+            charset: Some("utf-8".to_string()),
+            cgi_bin: None,
+            cgi_timeout: Duration::from_secs(30),
+            max_body_size: 16 * 1024 * 1024,
+            gzip: false,
+            gzip_static: false,
+            gzip_min_length: 1024,
+            gzip_level: 6,
+            error_pages: vec![],
+            error_format: ErrorFormat::Auto,
+            proxy_intercept_errors: false,
+            default_root: ".".to_string(),
+            vhosts: vec![],
+            writable: vec![],
+            auth_file: None,
+            auth_realm: "proxylab".to_string(),
+            proxy_auth_file: None,
+            proxy_auth_digest_file: None,
+            proxy_auth_realm: "proxylab".to_string(),
+            acl: Acl::default(),
+            blocklists: vec![],
+            parent_proxies: vec![],
+            socks_port: None,
+            routes: vec![],
+            pools: vec![],
+            health_checks: vec![],
+            outlier_detection: vec![],
+            admin_addr: None,
+            retry: retry::Policy::default(),
+            connect_attempt_delay: Some(Duration::from_millis(250)),
+            dns: dns::Settings::default(),
+            limits: limit::Settings::default(),
+            throttles: vec![],
+            faults: vec![],
+            har: har::Settings::default(),
+            replay: replay::Settings::default(),
+            mirrors: vec![],
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("reading {} failed: {:?}", path, e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    /// Parses a configuration made of `directive arg...` lines. `#` starts a comment at the
+    /// start of a line or after whitespace.
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        for (n, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let directive = words.next().unwrap_or_default();
+            let args = words.collect::<Vec<_>>();
+
+            config
+                .apply(directive, &args)
+                .map_err(|e| format!("line {}: {}", n + 1, e))?;
+        }
+
+        // Pools may be declared after the routes using them.
+        for route in config.routes.iter() {
+            if let reverse::Destination::Pool(name) = &route.destination {
+                if !config.pools.iter().any(|p| p.name == *name) {
+                    return Err(format!("route {} uses unknown pool `{}`", route.prefix, name));
+                }
+            }
+        }
+        let checked = config.health_checks.iter().map(|c| &c.pool);
+        let detected = config.outlier_detection.iter().map(|d| &d.pool);
+        for name in checked.chain(detected) {
+            if !config.pools.iter().any(|p| p.name == *name) {
+                return Err(format!("health settings for unknown pool `{}`", name));
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn apply(&mut self, directive: &str, args: &[&str]) -> Result<(), String> {
+        match directive {
+            "mime_types" => self.mime_types = Some(arg(args, 0)?.to_string()),
+            "mime_sniff" => self.mime_sniff = flag(args, 0)?,
+            "default_type" => self.default_type = arg(args, 0)?.to_string(),
+            "charset" => {
+                let charset = arg(args, 0)?;
+                self.charset = if charset == "off" {
+                    None
+                } else {
+                    Some(charset.to_string())
+                };
+            }
+            "cgi_bin" => self.cgi_bin = Some(arg(args, 0)?.trim_end_matches('/').to_string()),
+            "cgi_timeout" => self.cgi_timeout = Duration::from_secs(number(args, 0)?),
+            "max_body_size" => self.max_body_size = number(args, 0)?,
+            "gzip" => self.gzip = flag(args, 0)?,
+            "gzip_static" => self.gzip_static = flag(args, 0)?,
+            "gzip_min_length" => self.gzip_min_length = number(args, 0)?,
+            "gzip_level" => self.gzip_level = number::<u32>(args, 0)?.min(9),
+            "error_page" => {
+                let (path, statuses) = args
+                    .split_last()
+                    .filter(|(_, statuses)| !statuses.is_empty())
+                    .ok_or_else(|| "expected status codes and a path".to_string())?;
+                for i in 0..statuses.len() {
+                    self.error_pages.push((number(statuses, i)?, path.to_string()));
+                }
+            }
+            "error_format" => {
+                self.error_format = match arg(args, 0)? {
+                    "auto" => ErrorFormat::Auto,
+                    "html" => ErrorFormat::Html,
+                    "json" => ErrorFormat::Json,
+                    s => return Err(format!("unknown error format `{}`", s)),
+                }
+            }
+            "proxy_intercept_errors" => self.proxy_intercept_errors = flag(args, 0)?,
+            "document_root" => self.default_root = arg(args, 0)?.trim_end_matches('/').to_string(),
+            "vhost" => self.vhosts.push(VirtualHost::parse(args)?),
+            "writable" => {
+                arg(args, 0)?;
+                self.writable.extend(args.iter().map(|p| p.trim_end_matches('/').to_string()));
+            }
+            "auth_file" => self.auth_file = Some(arg(args, 0)?.to_string()),
+            "auth_realm" => self.auth_realm = arg(args, 0).map(|_| args.join(" "))?,
+            "proxy_auth_file" => self.proxy_auth_file = Some(arg(args, 0)?.to_string()),
+            "proxy_auth_digest_file" => {
+                self.proxy_auth_digest_file = Some(arg(args, 0)?.to_string())
+            }
+            "proxy_auth_realm" => self.proxy_auth_realm = arg(args, 0).map(|_| args.join(" "))?,
+            "acl" => {
+                let rule = acl::Rule::parse(args, self.acl.rules.len())?;
+                self.acl.rules.push(rule);
+            }
+            "acl_default" => {
+                self.acl.default = match arg(args, 0)? {
+                    "allow" => acl::Action::Allow,
+                    "deny" => acl::Action::Deny,
+                    s => return Err(format!("expected allow or deny, found `{}`", s)),
+                }
+            }
+            "acl_allow_private" => self.acl.allow_private = flag(args, 0)?,
+            "blocklist" => self.blocklists.push(blocklist::Source::parse(args)?),
+            "parent_proxy" => self.parent_proxies.push(upstream::Route::parse(args)?),
+            "socks_port" => self.socks_port = Some(number(args, 0)?),
+            "route" => self.routes.push(reverse::Route::parse(args)?),
+            "pool" => self.pools.push(balance::PoolConfig::parse(args)?),
+            "health_check" => self.health_checks.push(health::ActiveCheck::parse(args)?),
+            "outlier_detection" => {
+                let detection = health::OutlierDetection::parse(args)?;
+                self.outlier_detection.push(detection)
+            }
+            "admin_port" => {
+                let ip = match args.get(1) {
+                    Some(ip) => ip.parse().map_err(|_| format!("invalid address `{}`", ip))?,
+                    None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                };
+                self.admin_addr = Some(SocketAddr::new(ip, number(args, 0)?))
+            }
+            "retries" => self.retry.retries = number(args, 0)?,
+            "retry_backoff" => {
+                self.retry.backoff_base = Duration::from_millis(number(args, 0)?);
+                self.retry.backoff_max = Duration::from_millis(number(args, 1)?);
+            }
+            "retry_budget" => self.retry.budget = number(args, 0)?,
+            "connect_attempt_delay" => {
+                self.connect_attempt_delay = match arg(args, 0)? {
+                    "off" => None,
+                    _ => Some(Duration::from_millis(number(args, 0)?)),
+                }
+            }
+            "dns_ttl" => self.dns.ttl = Duration::from_secs(number(args, 0)?),
+            "dns_negative_ttl" => self.dns.negative_ttl = Duration::from_secs(number(args, 0)?),
+            "dns_timeout" => self.dns.timeout = Duration::from_secs(number(args, 0)?),
+            "dns_override" => self.dns.add_override(args)?,
+            "dns_hosts" => self.dns.load_hosts(arg(args, 0)?)?,
+            "rate_limit" => self.limits.requests = Some(limit::Rate::parse(args)?),
+            "byte_rate_limit" => self.limits.bytes = Some(limit::Rate::parse(args)?),
+            "user_rate_limit" => self.limits.user_requests = Some(limit::Rate::parse(args)?),
+            "max_connections" => self.limits.max_connections = Some(number(args, 0)?),
+            "max_connections_per_client" => {
+                self.limits.max_connections_per_client = Some(number(args, 0)?)
+            }
+            "throttle" => self.throttles.push(throttle::Rule::parse(args)?),
+            "fault" => self.faults.push(fault::Rule::parse(args)?),
+            "har" => self.har.prefix = Some(arg(args, 0)?.to_string()),
+            "har_body_limit" => self.har.body_limit = number(args, 0)?,
+            "har_rotate_size" => self.har.rotate_size = Some(number(args, 0)?),
+            "har_rotate_interval" => {
+                self.har.rotate_interval = Some(Duration::from_secs(number(args, 0)?))
+            }
+            "replay" => self.replay.sources.push(arg(args, 0)?.to_string()),
+            "replay_match_header" => {
+                arg(args, 0)?;
+                self.replay.headers.extend(args.iter().map(|h| h.to_string()));
+            }
+            "replay_match_body" => self.replay.body = flag(args, 0)?,
+            "replay_miss" => self.replay.miss = replay::Miss::parse(arg(args, 0)?)?,
+            "mirror" => self.mirrors.push(mirror::Rule::parse(args)?),
+            _ => return Err(format!("unknown directive `{}`", directive)),
+        }
+        Ok(())
+    }
+}
+
+/// Cuts the comment off `line`, leaving a `#` inside a value such as a regex or a URL.
+fn strip_comment(line: &str) -> &str {
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        if c == '#' && previous.is_whitespace() {
+            return &line[..i];
+        }
+        previous = c;
+    }
+    line
+}
+
+pub fn arg<'a>(args: &[&'a str], i: usize) -> Result<&'a str, String> {
+    args.get(i)
+        .map(|s| *s)
+        .ok_or_else(|| format!("missing argument {}", i + 1))
+}
+
+pub fn flag(args: &[&str], i: usize) -> Result<bool, String> {
+    match arg(args, i)? {
+        "on" | "yes" | "true" => Ok(true),
+        "off" | "no" | "false" => Ok(false),
+        s => Err(format!("expected on or off, found `{}`", s)),
+    }
+}
+
+pub fn number<T: FromStr>(args: &[&str], i: usize) -> Result<T, String> {
+    let s = arg(args, i)?;
+    s.parse().map_err(|_| format!("invalid number `{}`", s))
+}
+
+/// Parses `key=value` options, as in `health_check` and `throttle`.
+pub fn options<'a>(args: &[&'a str]) -> Result<Vec<(&'a str, &'a str)>, String> {
+    args.iter()
+        .map(|option| {
+            let mut split = option.splitn(2, '=');
+            let key = split.next().unwrap_or_default();
+            split
+                .next()
+                .map(|value| (key, value))
+                .ok_or_else(|| format!("expected key=value, found `{}`", option))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments() {
+        assert_eq!(strip_comment("# a comment"), "");
+        assert_eq!(strip_comment("gzip on # compress"), "gzip on ");
+        assert_eq!(strip_comment("gzip on\t#compress"), "gzip on\t");
+        assert_eq!(strip_comment("acl deny host=~^a#b$"), "acl deny host=~^a#b$");
+        let proxy = "parent_proxy ~intra#net proxy:3128 # egress";
+        assert_eq!(strip_comment(proxy), "parent_proxy ~intra#net proxy:3128 ");
+    }
+}
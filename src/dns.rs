@@ -0,0 +1,225 @@
+use crate::*;
+use futures::{channel::oneshot, prelude::*};
+use std::{
+    collections::HashMap,
+    fs,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Resolver settings from the `dns_*` directives.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// How long a successful lookup is reused; the system resolver does not report TTLs.
+    pub ttl: Duration,
+    /// How long a failed lookup is reused.
+    pub negative_ttl: Duration,
+    pub timeout: Duration,
+    /// Names answered without a lookup, from `dns_override` and `dns_hosts`.
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Adds a `dns_override <host> <ip>...` line.
+    pub fn add_override(&mut self, args: &[&str]) -> Result<(), String> {
+        if args.len() < 2 {
+            return Err("expected a host name and at least one address".to_string());
+        }
+        let addrs = args[1..]
+            .iter()
+            .map(|a| a.parse().map_err(|_| format!("invalid address `{}`", a)))
+            .collect::<Result<Vec<IpAddr>, String>>()?;
+        self.overrides
+            .entry(args[0].to_lowercase())
+            .or_insert_with(Vec::new)
+            .extend(addrs);
+        Ok(())
+    }
+
+    /// Adds every entry of a file in `/etc/hosts` format.
+    pub fn load_hosts(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("reading hosts file {} failed: {:?}", path, e))?;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let ip = match words.next().map(|w| w.parse::<IpAddr>()) {
+                Some(Ok(ip)) => ip,
+                Some(Err(_)) => return Err(format!("{}: invalid address in `{}`", path, line)),
+                None => continue,
+            };
+            for name in words {
+                self.overrides
+                    .entry(name.to_lowercase())
+                    .or_insert_with(Vec::new)
+                    .push(ip);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Answers kept before expired ones are dropped, then the ones closest to expiring.
+const MAX_CACHED: usize = 4096;
+/// Threads blocked in the system resolver at most.
+const RESOLVER_THREADS: usize = 4;
+/// Names waiting for a resolver thread before further lookups are refused.
+const RESOLVER_QUEUE: usize = 256;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    addrs: Result<Vec<IpAddr>, String>,
+    expires: Instant,
+}
+
+type Answer = Result<Vec<IpAddr>, String>;
+
+lazy_static! {
+    static ref SETTINGS: RwLock<Arc<Settings>> = RwLock::new(Arc::new(Settings::default()));
+    static ref CACHE: Mutex<HashMap<String, Entry>> = Mutex::new(HashMap::new());
+    /// Names being looked up, with everyone waiting for their answer.
+    static ref PENDING: Mutex<HashMap<String, Vec<oneshot::Sender<Answer>>>> =
+        Mutex::new(HashMap::new());
+    static ref LOOKUPS: Mutex<SyncSender<String>> = Mutex::new(start_resolvers());
+}
+
+/// Replaces the resolver settings and forgets every cached answer.
+pub fn configure(settings: Settings) {
+    *SETTINGS.write().unwrap() = Arc::new(settings);
+    CACHE.lock().unwrap().clear();
+}
+
+/// Looks up `host`, from an override, the cache, or the system resolver on a pool thread.
+/// Concurrent lookups of the same name share one query.
+pub async fn resolve(host: String) -> Result<Vec<IpAddr>, HttpError> {
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+
+    let settings = SETTINGS.read().unwrap().clone();
+    if let Some(addrs) = settings.overrides.get(&host) {
+        return Ok(addrs.clone());
+    }
+
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut pending = PENDING.lock().unwrap();
+        if let Some(entry) = CACHE.lock().unwrap().get(&host) {
+            if entry.expires > Instant::now() {
+                return entry.addrs.clone().map_err(|e| failed(&host, e));
+            }
+        }
+
+        let waiters = pending.entry(host.clone()).or_insert_with(Vec::new);
+        waiters.push(tx);
+        if waiters.len() == 1 && LOOKUPS.lock().unwrap().try_send(host.clone()).is_err() {
+            let waiters = pending.remove(&host).unwrap_or_default();
+            for tx in waiters {
+                let _ = tx.send(Err("too many lookups in progress".to_string()));
+            }
+        }
+    }
+
+    // A lookup that outlasts the timeout still finishes and is cached for the next caller.
+    let answer = rx.map(|answer| {
+        answer.map_err(|_| HttpError::Internal("resolver thread failed".to_string()))
+    });
+    match await!(with_timeout(answer, settings.timeout)) {
+        Ok(addrs) => addrs.map_err(|e| failed(&host, e)),
+        Err(e) => Err(failed(&host, e.info().3)),
+    }
+}
+
+fn failed(host: &str, e: String) -> HttpError {
+    HttpError::BadGateway(format!("resolving {} failed: {}", host, e))
+}
+
+fn start_resolvers() -> SyncSender<String> {
+    let (tx, rx) = mpsc::sync_channel::<String>(RESOLVER_QUEUE);
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..RESOLVER_THREADS {
+        let rx = rx.clone();
+        thread::spawn(move || loop {
+            let host = match rx.lock().unwrap().recv() {
+                Ok(host) => host,
+                Err(_) => return,
+            };
+            let addrs = lookup(&host);
+            finish(host, addrs);
+        });
+    }
+    tx
+}
+
+fn lookup(host: &str) -> Answer {
+    match (host, 0).to_socket_addrs() {
+        Ok(addrs) => {
+            let addrs = addrs.map(|a| a.ip()).collect::<Vec<_>>();
+            if addrs.is_empty() {
+                Err("no addresses".to_string())
+            } else {
+                Ok(addrs)
+            }
+        }
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
+/// Caches the answer for `host` and hands it to everyone waiting for it.
+fn finish(host: String, addrs: Answer) {
+    let settings = SETTINGS.read().unwrap().clone();
+    let ttl = if addrs.is_ok() {
+        settings.ttl
+    } else {
+        settings.negative_ttl
+    };
+
+    let mut pending = PENDING.lock().unwrap();
+    let mut cache = CACHE.lock().unwrap();
+    if cache.len() >= MAX_CACHED {
+        let now = Instant::now();
+        cache.retain(|_, entry| entry.expires > now);
+    }
+    if cache.len() >= MAX_CACHED {
+        let soonest = cache
+            .iter()
+            .min_by_key(|(_, entry)| entry.expires)
+            .map(|(name, _)| name.clone());
+        if let Some(name) = soonest {
+            cache.remove(&name);
+        }
+    }
+    let entry = Entry {
+        addrs: addrs.clone(),
+        expires: Instant::now() + ttl,
+    };
+    cache.insert(host.clone(), entry);
+    drop(cache);
+
+    for tx in pending.remove(&host).unwrap_or_default() {
+        let _ = tx.send(addrs.clone());
+    }
+}
+
+pub async fn resolve_addrs(host: String, port: u16) -> Result<Vec<SocketAddr>, HttpError> {
+    let addrs = await!(resolve(host))?;
+    Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+}
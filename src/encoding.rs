@@ -0,0 +1,106 @@
+use crate::mime::is_text;
+use flate2::{write::GzEncoder, Compression};
+use futures::compat::*;
+use std::{
+    fs::{self, File},
+    io::Write,
+};
+use tokio::{io, prelude::AsyncWrite};
+
+/// Codings `precompressed` looks for, as (coding, file suffix), in order of preference.
+const PRECOMPRESSED: &[(&str, &str)] = &[("br", ".br"), ("gzip", ".gz")];
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returns the quality the client gave `coding` in an `Accept-Encoding` value.
+pub fn quality(accept_encoding: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|p| {
+                let p = p.trim();
+                if p.starts_with("q=") {
+                    p[2..].parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(coding)
+            || (coding == "gzip" && name.eq_ignore_ascii_case("x-gzip"))
+        {
+            return q;
+        } else if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+
+    wildcard.unwrap_or(0.0)
+}
+
+pub fn accepts(accept_encoding: Option<&str>, coding: &str) -> bool {
+    accept_encoding.map_or(false, |ae| quality(ae, coding) > 0.0)
+}
+
+/// Whether on-the-fly compression is worth it for `mime`.
+pub fn is_compressible(mime: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    is_text(mime) || mime == "application/wasm" || mime == "application/vnd.ms-fontobject"
+}
+
+/// Finds a precompressed sibling of `filename` that the client accepts, returning the
+/// coding and the sibling's path.
+pub fn precompressed(filename: &str, accept_encoding: Option<&str>) -> Option<(String, String)> {
+    let accept_encoding = accept_encoding?;
+
+    let mut candidates = PRECOMPRESSED
+        .iter()
+        .map(|(coding, suffix)| (quality(accept_encoding, coding), *coding, *suffix))
+        .filter(|(q, _, _)| *q > 0.0)
+        .collect::<Vec<_>>();
+    // Stable sort keeps the preference order between equal qualities.
+    candidates.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+
+    candidates.into_iter().find_map(|(_, coding, suffix)| {
+        let sibling = format!("{}{}", filename, suffix);
+        fs::metadata(&sibling)
+            .ok()
+            .filter(|m| m.is_file())
+            .map(|_| (coding.to_string(), sibling))
+    })
+}
+
+/// Gzips `file` into `writer` chunk by chunk. The output length is unknown up front,
+/// so the caller must delimit the body by closing the connection.
+pub async fn gzip_file<W: AsyncWrite>(file: File, writer: W, level: u32) -> Result<W, io::Error> {
+    let mut file = tokio::fs::File::from_std(file);
+    let mut writer = writer;
+    let mut encoder = GzEncoder::new(vec![], Compression::new(level));
+    let mut buf = vec![0; CHUNK_SIZE];
+
+    loop {
+        let (f, b, n) = await!(io::read(file, buf).compat())?;
+        file = f;
+        buf = b;
+        if n == 0 {
+            break;
+        }
+
+        encoder.write_all(&buf[..n])?;
+        let out = std::mem::replace(encoder.get_mut(), vec![]);
+        if !out.is_empty() {
+            let (w, _) = await!(io::write_all(writer, out).compat())?;
+            writer = w;
+        }
+    }
+
+    let out = encoder.finish()?;
+    let (writer, _) = await!(io::write_all(writer, out).compat())?;
+
+    Ok(writer)
+}
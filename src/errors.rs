@@ -0,0 +1,167 @@
+use crate::*;
+use std::{collections::HashMap, fs};
+
+const DEFAULT_TEMPLATE: &str = "<html><head><title>Mini Error</title></head><body bgcolor=ffffff>\r\n\
+                                <b>{status}: {reason}</b>\r\n\
+                                <p>{message}: {detail}\r\n\
+                                <hr><em>Mini Web server</em></body></html>\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    /// JSON when the client's `Accept` prefers it over HTML.
+    Auto,
+    Html,
+    Json,
+}
+
+/// Renders error responses from per-status HTML templates.
+///
+/// Templates may contain `{status}`, `{reason}`, `{message}` and `{detail}`, which are
+/// replaced with HTML-escaped values.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    templates: HashMap<u16, String>,
+    format: ErrorFormat,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        ErrorPages {
+            templates: HashMap::new(),
+            format: ErrorFormat::Html,
+        }
+    }
+}
+
+impl ErrorPages {
+    pub fn from_config(config: &crate::config::Config) -> Result<Self, String> {
+        let mut templates = HashMap::new();
+        for (status, path) in config.error_pages.iter() {
+            let template = fs::read_to_string(path)
+                .map_err(|e| format!("reading error page {} failed: {:?}", path, e))?;
+            templates.insert(*status, template);
+        }
+
+        Ok(ErrorPages {
+            templates,
+            format: config.error_format,
+        })
+    }
+
+    pub fn render(
+        &self,
+        status: u16,
+        reason: &str,
+        message: &str,
+        detail: &str,
+        accept: Option<&str>,
+    ) -> Response {
+        let json = match self.format {
+            ErrorFormat::Json => true,
+            ErrorFormat::Html => false,
+            ErrorFormat::Auto => accept.map_or(false, |accept| {
+                accept_quality(accept, "application/json") > accept_quality(accept, "text/html")
+            }),
+        };
+
+        let (content_type, body) = if json {
+            (
+                "application/json",
+                format!(
+                    "{{\"status\":{},\"reason\":{},\"message\":{},\"detail\":{}}}",
+                    status,
+                    json_string(reason),
+                    json_string(message),
+                    json_string(detail)
+                ),
+            )
+        } else {
+            let template = self
+                .templates
+                .get(&status)
+                .map(|t| t.as_str())
+                .unwrap_or(DEFAULT_TEMPLATE);
+            (
+                "text/html",
+                template
+                    .replace("{status}", &status.to_string())
+                    .replace("{reason}", &html_escape(reason))
+                    .replace("{message}", &html_escape(message))
+                    .replace("{detail}", &html_escape(detail)),
+            )
+        };
+
+        Response {
+            version: "HTTP/1.0".to_string(),
+            status,
+            reason: reason.to_string(),
+            headers: vec![
+                format!("Content-Type: {}", content_type),
+                format!("Content-Length: {}", body.len()),
+            ],
+            content: body.into_bytes(),
+        }
+    }
+}
+
+/// Returns the quality `accept` gives `mime`, honoring `type/*` and `*/*` ranges.
+pub fn accept_quality(accept: &str, mime: &str) -> f32 {
+    let major = mime.split('/').next().unwrap_or_default();
+    let mut best: Option<(u8, f32)> = None;
+
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let range = params.next().unwrap_or_default().trim().to_lowercase();
+        let q = params
+            .filter_map(|p| {
+                let p = p.trim();
+                if p.starts_with("q=") {
+                    p[2..].parse::<f32>().ok()
+                } else {
+                    None
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+
+        let specificity = if range == mime {
+            2
+        } else if range == format!("{}/*", major) {
+            1
+        } else if range == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        if best.map_or(true, |(s, _)| specificity > s) {
+            best = Some((specificity, q));
+        }
+    }
+
+    best.map(|(_, q)| q).unwrap_or(0.0)
+}
+
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
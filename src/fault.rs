@@ -0,0 +1,145 @@
+use crate::{acl::HostPattern, config::number, *};
+use std::time::Duration;
+use tokio::io;
+
+/// How a response that did arrive is spoiled on its way to the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Damage {
+    /// Send the head and half the body, then reset the connection.
+    Reset,
+    /// Send the head and half the body, then close the connection normally.
+    Truncate,
+    /// Send everything with some bytes of the body flipped.
+    Corrupt,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    /// Answer with this status without contacting the upstream.
+    Status(u16),
+    /// Wait this long before contacting the upstream.
+    Delay(Duration),
+    Damage(Damage),
+}
+
+/// A `fault <host pattern|*> <path prefix> <status=N|delay=ms|reset|truncate|corrupt>
+/// [probability=<percent>]` directive.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Option<HostPattern>,
+    prefix: String,
+    pub action: Action,
+    /// Percentage of matching requests the fault fires for.
+    pub probability: f64,
+}
+
+impl Rule {
+    pub fn parse(args: &[&str]) -> Result<Rule, String> {
+        if args.len() < 3 || args.len() > 4 {
+            return Err(
+                "expected a host pattern, a path prefix, an action and a probability".to_string(),
+            );
+        }
+
+        let pattern = match args[0] {
+            "*" => None,
+            p => Some(HostPattern::parse(p)?),
+        };
+
+        let mut split = args[2].splitn(2, '=');
+        let action = match (split.next().unwrap_or_default(), split.next()) {
+            ("status", Some(status)) => Action::Status(
+                number(&[status], 0)
+                    .ok()
+                    .filter(|s| *s >= 100 && *s < 600)
+                    .ok_or_else(|| format!("invalid status `{}`", status))?,
+            ),
+            ("delay", Some(millis)) => Action::Delay(Duration::from_millis(number(&[millis], 0)?)),
+            ("reset", None) => Action::Damage(Damage::Reset),
+            ("truncate", None) => Action::Damage(Damage::Truncate),
+            ("corrupt", None) => Action::Damage(Damage::Corrupt),
+            _ => return Err(format!("unknown fault `{}`", args[2])),
+        };
+
+        let probability = match args.get(3) {
+            Some(p) if p.starts_with("probability=") => {
+                let percent = &p["probability=".len()..];
+                number::<f64>(&[percent], 0)
+                    .ok()
+                    .filter(|p| *p >= 0.0 && *p <= 100.0)
+                    .ok_or_else(|| format!("invalid probability `{}`", percent))?
+            }
+            Some(p) => return Err(format!("expected probability=<percent>, found `{}`", p)),
+            None => 100.0,
+        };
+
+        Ok(Rule {
+            pattern,
+            prefix: args[1].to_string(),
+            action,
+            probability,
+        })
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        self.pattern.as_ref().map_or(true, |p| p.matches(&req.uri.host))
+            && req.uri.path.starts_with(self.prefix.as_str())
+    }
+}
+
+/// The faults that fired for one request.
+#[derive(Debug, Default)]
+pub struct Faults {
+    pub delay: Duration,
+    pub status: Option<u16>,
+    pub damage: Option<Damage>,
+}
+
+/// Rolls every rule matching `req`. Delays add up; for statuses and damage the first
+/// rule to fire wins.
+pub fn roll(rules: &[Rule], req: &Request) -> Faults {
+    let mut faults = Faults::default();
+    let fired = rules
+        .iter()
+        .filter(|r| r.matches(req) && rand::random::<f64>() * 100.0 < r.probability);
+
+    for rule in fired {
+        match rule.action {
+            Action::Delay(delay) => faults.delay += delay,
+            Action::Status(status) => faults.status = faults.status.or(Some(status)),
+            Action::Damage(damage) => faults.damage = faults.damage.or(Some(damage)),
+        }
+    }
+    faults
+}
+
+/// Flips random bits in about one byte per thousand of `content`, at least one.
+pub fn corrupt(content: &mut [u8]) {
+    if content.is_empty() {
+        return;
+    }
+    for _ in 0..(content.len() / 1000).max(1) {
+        let i = rand::random::<usize>() % content.len();
+        content[i] ^= rand::random::<u8>() | 1;
+    }
+}
+
+/// Sends `resp` spoiled by `damage` and hands the writer back, so the caller decides how
+/// the connection ends.
+pub async fn send_damaged<W: AsyncWrite>(
+    writer: W,
+    resp: Response,
+    damage: Damage,
+) -> Result<W, io::Error> {
+    let mut resp = resp;
+    let mut content = std::mem::replace(&mut resp.content, vec![]);
+    if damage == Damage::Corrupt {
+        corrupt(&mut content);
+    } else {
+        content.truncate(content.len() / 2);
+    }
+
+    let writer = await!(response_head(writer, resp))?;
+    let (writer, _) = await!(io::write_all(writer, content).compat())?;
+    Ok(writer)
+}
@@ -0,0 +1,90 @@
+use futures::compat::*;
+use std::fs::File;
+use tokio::{io, net::TcpStream, prelude::AsyncWrite};
+
+/// Largest piece of a file held in memory at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Copies `len` bytes of `file` to `writer`, one chunk at a time.
+pub async fn copy_file<W: AsyncWrite>(file: File, writer: W, len: u64) -> Result<W, io::Error> {
+    let mut file = tokio::fs::File::from_std(file);
+    let mut writer = writer;
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let (f, mut b, n) = await!(io::read(file, buf).compat())?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while sending",
+            ));
+        }
+        let n = n.min(remaining as usize);
+        b.truncate(n);
+        let (w, mut b) = await!(io::write_all(writer, b).compat())?;
+        b.resize(CHUNK_SIZE, 0);
+
+        file = f;
+        writer = w;
+        buf = b;
+        remaining -= n as u64;
+    }
+
+    Ok(writer)
+}
+
+/// Sends `len` bytes of `file` to `stream`, using `sendfile(2)` so the content never
+/// passes through user space.
+#[cfg(target_os = "linux")]
+pub async fn send_file(stream: TcpStream, file: File, len: u64) -> Result<TcpStream, io::Error> {
+    use std::os::unix::{fs::FileExt, io::AsRawFd};
+
+    let mut stream = stream;
+    let mut offset = 0u64;
+
+    while offset < len {
+        let count = (len - offset).min(CHUNK_SIZE as u64 * 16) as usize;
+        let mut off = offset as libc::off_t;
+        let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut off, count) };
+
+        if sent > 0 {
+            offset += sent as u64;
+            continue;
+        } else if sent == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while sending",
+            ));
+        }
+
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::Interrupted => {}
+            io::ErrorKind::WouldBlock => {
+                // The socket buffer is full. Push one chunk through tokio so the task is
+                // parked until the socket becomes writable again.
+                let mut buf = vec![0; count.min(CHUNK_SIZE)];
+                let n = file.read_at(&mut buf, offset)?;
+                if n == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while sending",
+                    ));
+                }
+                buf.truncate(n);
+                let (s, _) = await!(io::write_all(stream, buf).compat())?;
+                stream = s;
+                offset += n as u64;
+            }
+            _ => return Err(e),
+        }
+    }
+
+    Ok(stream)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_file(stream: TcpStream, file: File, len: u64) -> Result<TcpStream, io::Error> {
+    await!(copy_file(file, stream, len))
+}
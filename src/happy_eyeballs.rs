@@ -0,0 +1,112 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io,
+    net::{tcp::ConnectFuture, TcpStream},
+    prelude::{Async, Future, Poll},
+    timer::Delay,
+};
+
+/// Milliseconds to wait on an attempt before starting the next one; 0 waits for it to
+/// fail, trying one address at a time.
+static ATTEMPT_DELAY: AtomicUsize = AtomicUsize::new(250);
+
+/// Sets the Connection Attempt Delay, or `None` for plain sequential fallback.
+pub fn set_attempt_delay(delay: Option<Duration>) {
+    let millis = delay.map_or(0, |d| d.as_secs() * 1000 + u64::from(d.subsec_millis()));
+    ATTEMPT_DELAY.store(millis as usize, Ordering::Relaxed);
+}
+
+/// Interleaves the address families, starting with the family the resolver put first.
+pub fn sort(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let first_v6 = addrs.first().map_or(false, |a| a.is_ipv6());
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_v6);
+
+    let mut sorted = VecDeque::new();
+    while !first.is_empty() || !second.is_empty() {
+        sorted.extend(first.pop_front());
+        sorted.extend(second.pop_front());
+    }
+    sorted
+}
+
+/// Races connections to `addrs` as RFC 8305 describes, resolving to the first one
+/// established and dropping the attempts still pending.
+pub struct Connect {
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<ConnectFuture>,
+    delay: Option<Duration>,
+    next_attempt: Option<Delay>,
+    last_error: Option<io::Error>,
+}
+
+pub fn connect(addrs: Vec<SocketAddr>) -> Connect {
+    let millis = ATTEMPT_DELAY.load(Ordering::Relaxed) as u64;
+    Connect {
+        addrs: sort(addrs),
+        attempts: vec![],
+        delay: if millis == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(millis))
+        },
+        next_attempt: None,
+        last_error: None,
+    }
+}
+
+impl Future for Connect {
+    type Item = TcpStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, io::Error> {
+        loop {
+            let mut failed = false;
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].poll() {
+                    Ok(Async::Ready(stream)) => return Ok(Async::Ready(stream)),
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        self.attempts.remove(i);
+                        self.last_error = Some(e);
+                        failed = true;
+                    }
+                }
+            }
+
+            // A failure starts the next attempt at once instead of waiting out the delay.
+            let delay_elapsed = match self.next_attempt.as_mut().map(|d| d.poll()) {
+                Some(Ok(Async::NotReady)) => false,
+                Some(_) => true,
+                None => false,
+            };
+            let start_next = self.attempts.is_empty() || failed || delay_elapsed;
+
+            match self.addrs.pop_front() {
+                Some(addr) if start_next => {
+                    self.attempts.push(TcpStream::connect(&addr));
+                    self.next_attempt = self.delay.map(|d| Delay::new(Instant::now() + d));
+                }
+                Some(addr) => {
+                    self.addrs.push_front(addr);
+                    return Ok(Async::NotReady);
+                }
+                None if self.attempts.is_empty() => {
+                    return Err(self.last_error.take().unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")
+                    }));
+                }
+                None => {
+                    self.next_attempt = None;
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
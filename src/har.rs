@@ -0,0 +1,363 @@
+use crate::{errors::json_string, *};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, SyncSender},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Closes the `entries` array and the document after the last entry; each new entry is
+/// written over it, so the file is valid JSON between writes.
+const TRAILER: &str = "\n]}}\n";
+
+/// The `har <path prefix>`, `har_body_limit <bytes>`, `har_rotate_size <bytes>` and
+/// `har_rotate_interval <seconds>` settings.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Files are named `<prefix>-<unix time>.har`; recording is off without one.
+    pub prefix: Option<String>,
+    /// Bodies are cut to this many bytes.
+    pub body_limit: usize,
+    pub rotate_size: Option<u64>,
+    pub rotate_interval: Option<Duration>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            prefix: None,
+            body_limit: 64 * 1024,
+            rotate_size: None,
+            rotate_interval: None,
+        }
+    }
+}
+
+/// How long each phase of one exchange took; phases that did not happen are `None`.
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub send: Duration,
+    pub wait: Duration,
+    pub receive: Duration,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + f64::from(d.subsec_nanos()) / 1e6
+}
+
+impl Timings {
+    fn to_json(&self) -> String {
+        let optional = |d: Option<Duration>| d.map_or(-1.0, millis);
+        format!(
+            "{{\"blocked\": -1, \"dns\": {:.3}, \"connect\": {:.3}, \"send\": {:.3}, \
+             \"wait\": {:.3}, \"receive\": {:.3}, \"ssl\": -1}}",
+            optional(self.dns),
+            optional(self.connect),
+            millis(self.send),
+            millis(self.wait),
+            millis(self.receive)
+        )
+    }
+
+    fn total(&self) -> f64 {
+        let optional = |d: Option<Duration>| d.map_or(0.0, millis);
+        optional(self.dns)
+            + optional(self.connect)
+            + millis(self.send)
+            + millis(self.wait)
+            + millis(self.receive)
+    }
+}
+
+/// Formats `time` as ISO 8601 in UTC, which HAR requires for `startedDateTime`.
+fn iso8601(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, seconds) = (since.as_secs() / 86400, since.as_secs() % 86400);
+
+    // Days to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        since.subsec_millis()
+    )
+}
+
+fn pairs_json(pairs: &[(String, String)]) -> String {
+    let pairs = pairs
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{{\"name\": {}, \"value\": {}}}",
+                json_string(name),
+                json_string(value)
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", pairs.join(", "))
+}
+
+fn headers(headers: &[String]) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|h| {
+            let mut split = h.splitn(2, ':');
+            let name = split.next().unwrap_or_default().trim().to_string();
+            (name, split.next().unwrap_or_default().trim().to_string())
+        })
+        .collect()
+}
+
+/// Splits `a=1; b=2` or `a=1&b=2` style lists on `separator`.
+fn name_values(s: &str, separator: char) -> Vec<(String, String)> {
+    s.split(separator)
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut split = pair.splitn(2, '=');
+            let name = split.next().unwrap_or_default().to_string();
+            (name, split.next().unwrap_or_default().to_string())
+        })
+        .collect()
+}
+
+/// The `text` of a body cut to `limit`, base64 encoded unless it is UTF-8, with the
+/// fields that say so.
+fn body_json(body: &[u8], limit: usize) -> String {
+    let mut kept = body.len().min(limit);
+    let mut fields = match std::str::from_utf8(body) {
+        Ok(text) => {
+            while !text.is_char_boundary(kept) {
+                kept -= 1;
+            }
+            format!("\"text\": {}", json_string(&text[..kept]))
+        }
+        Err(_) => format!(
+            "\"text\": {}, \"encoding\": \"base64\"",
+            json_string(&base64::encode(&body[..kept]))
+        ),
+    };
+    if kept < body.len() {
+        fields += &format!(
+            ", \"comment\": {}",
+            json_string(&format!("truncated to {} of {} bytes", kept, body.len()))
+        );
+    }
+    fields
+}
+
+/// Builds one HAR entry for `req` answered by `resp`.
+pub fn entry(
+    started: SystemTime,
+    req: &Request,
+    resp: &Response,
+    timings: &Timings,
+    body_limit: usize,
+) -> String {
+    let query = req
+        .uri
+        .path
+        .splitn(2, '?')
+        .nth(1)
+        .map(|q| name_values(q, '&'))
+        .unwrap_or_default();
+    let cookies = req
+        .header("Cookie")
+        .map(|c| name_values(c, ';'))
+        .unwrap_or_default();
+    let post_data = if req.body.is_empty() {
+        String::new()
+    } else {
+        format!(
+            ", \"postData\": {{\"mimeType\": {}, \"params\": [], {}}}",
+            json_string(req.header("Content-Type").unwrap_or_default()),
+            body_json(&req.body, body_limit)
+        )
+    };
+    let request = format!(
+        "{{\"method\": {}, \"url\": {}, \"httpVersion\": {}, \"cookies\": {}, \
+         \"headers\": {}, \"queryString\": {}{}, \"headersSize\": -1, \"bodySize\": {}}}",
+        json_string(&req.method),
+        json_string(&req.uri.to_string()),
+        json_string(&req.version),
+        pairs_json(&cookies),
+        pairs_json(&headers(&req.headers)),
+        pairs_json(&query),
+        post_data,
+        req.body.len()
+    );
+
+    let set_cookies = headers(&resp.headers)
+        .into_iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Set-Cookie"))
+        .filter_map(|(_, value)| name_values(&value, ';').into_iter().next())
+        .collect::<Vec<_>>();
+    let response = format!(
+        "{{\"status\": {}, \"statusText\": {}, \"httpVersion\": {}, \"cookies\": {}, \
+         \"headers\": {}, \"content\": {{\"size\": {}, \"mimeType\": {}, {}}}, \
+         \"redirectURL\": {}, \"headersSize\": -1, \"bodySize\": {}}}",
+        resp.status,
+        json_string(&resp.reason),
+        json_string(&resp.version),
+        pairs_json(&set_cookies),
+        pairs_json(&headers(&resp.headers)),
+        resp.content.len(),
+        json_string(resp.header("Content-Type").unwrap_or_default()),
+        body_json(&resp.content, body_limit),
+        json_string(resp.header("Location").unwrap_or_default()),
+        resp.content.len()
+    );
+
+    format!(
+        "{{\"startedDateTime\": {}, \"time\": {:.3}, \"request\": {}, \"response\": {}, \
+         \"cache\": {{}}, \"timings\": {}}}",
+        json_string(&iso8601(started)),
+        timings.total(),
+        request,
+        response,
+        timings.to_json()
+    )
+}
+
+#[derive(Debug)]
+struct Current {
+    file: File,
+    opened: Instant,
+    size: u64,
+    entries: usize,
+}
+
+/// Entries waiting for the writer thread before further ones are dropped.
+const WRITE_QUEUE: usize = 1024;
+
+/// Hands entries to a writer thread of its own, which appends them to the current HAR file.
+#[derive(Debug)]
+pub struct Recorder {
+    body_limit: usize,
+    entries: Mutex<SyncSender<String>>,
+}
+
+impl Recorder {
+    /// Returns `None` when recording is off.
+    pub fn new(settings: &Settings) -> Option<Recorder> {
+        let prefix = settings.prefix.clone()?;
+        let (tx, rx) = mpsc::sync_channel::<String>(WRITE_QUEUE);
+        let mut writer = Writer {
+            prefix,
+            settings: settings.clone(),
+            current: None,
+        };
+        thread::spawn(move || {
+            for entry in rx {
+                if let Err(e) = writer.append(&entry) {
+                    eprintln!("har: writing failed: {:?}", e);
+                }
+            }
+        });
+
+        Some(Recorder {
+            body_limit: settings.body_limit,
+            entries: Mutex::new(tx),
+        })
+    }
+
+    pub fn body_limit(&self) -> usize {
+        self.body_limit
+    }
+
+    /// Queues `entry` for writing; when the writer is behind it is dropped and the exchange
+    /// goes on.
+    pub fn record(&self, entry: String) {
+        if self.entries.lock().unwrap().try_send(entry).is_err() {
+            eprintln!("har: writer behind, entry dropped");
+        }
+    }
+}
+
+/// Appends entries to the current HAR file, starting a new one when it gets too big or
+/// too old.
+struct Writer {
+    prefix: String,
+    settings: Settings,
+    current: Option<Current>,
+}
+
+impl Writer {
+
+    fn is_due(&self, current: &Current) -> bool {
+        self.settings.rotate_size.map_or(false, |max| current.size >= max)
+            || self
+                .settings
+                .rotate_interval
+                .map_or(false, |max| current.opened.elapsed() >= max)
+    }
+
+    fn open(&self) -> std::io::Result<Current> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut path = format!("{}-{}.har", self.prefix, now);
+        let mut n = 1;
+        while Path::new(&path).exists() {
+            path = format!("{}-{}-{}.har", self.prefix, now, n);
+            n += 1;
+        }
+
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&path)?;
+        let head = format!(
+            "{{\"log\": {{\"version\": \"1.2\", \"creator\": {{\"name\": \"proxylab\", \
+             \"version\": {}}}, \"entries\": [",
+            json_string(env!("CARGO_PKG_VERSION"))
+        );
+        file.write_all(head.as_bytes())?;
+        file.write_all(TRAILER.as_bytes())?;
+        println!("har: recording to {}\n", path);
+
+        Ok(Current {
+            file,
+            opened: Instant::now(),
+            size: (head.len() + TRAILER.len()) as u64,
+            entries: 0,
+        })
+    }
+
+    fn append(&mut self, entry: &str) -> std::io::Result<()> {
+        if self.current.as_ref().map_or(true, |c| self.is_due(c)) {
+            self.current = Some(self.open()?);
+        }
+        let current = self.current.as_mut().unwrap();
+
+        let separator = if current.entries == 0 { "\n" } else { ",\n" };
+        let offset = current.size - TRAILER.len() as u64;
+        current.file.seek(SeekFrom::Start(offset))?;
+        current.file.write_all(separator.as_bytes())?;
+        current.file.write_all(entry.as_bytes())?;
+        current.file.write_all(TRAILER.as_bytes())?;
+
+        current.size = offset + (separator.len() + entry.len() + TRAILER.len()) as u64;
+        current.entries += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,304 @@
+use crate::{
+    balance::{Backend, Pools},
+    config::{number, options},
+    upstream, *,
+};
+use std::{
+    io::BufReader,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::io;
+
+/// A `health_check <pool> [path=/] [status=200] [interval=10] [timeout=2] [rise=2]
+/// [fall=3]` directive; times are in seconds.
+#[derive(Debug, Clone)]
+pub struct ActiveCheck {
+    pub pool: String,
+    pub path: String,
+    pub status: u16,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive passing probes that bring a backend back up.
+    pub rise: u32,
+    /// Consecutive failing probes that take a backend down.
+    pub fall: u32,
+}
+
+impl ActiveCheck {
+    pub fn parse(args: &[&str]) -> Result<ActiveCheck, String> {
+        let pool = config::arg(args, 0)?;
+        let mut check = ActiveCheck {
+            pool: pool.to_string(),
+            path: "/".to_string(),
+            status: 200,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            rise: 2,
+            fall: 3,
+        };
+
+        for (key, value) in options(&args[1..])? {
+            match key {
+                "path" => check.path = value.to_string(),
+                "status" => check.status = number(&[value], 0)?,
+                "interval" => check.interval = Duration::from_secs(number(&[value], 0)?),
+                "timeout" => check.timeout = Duration::from_secs(number(&[value], 0)?),
+                "rise" => check.rise = number(&[value], 0)?,
+                "fall" => check.fall = number(&[value], 0)?,
+                _ => return Err(format!("unknown health_check option `{}`", key)),
+            }
+        }
+
+        Ok(check)
+    }
+}
+
+/// An `outlier_detection <pool> [failures=5] [error_rate=50] [min_requests=10] [window=30]
+/// [ejection=30]` directive; the rate is a percentage and times are in seconds.
+///
+/// A backend is ejected after `failures` consecutive failed requests, or when at least
+/// `min_requests` within `window` failed at `error_rate` or more. Connection errors and
+/// 5xx responses count as failures.
+#[derive(Debug, Clone)]
+pub struct OutlierDetection {
+    pub pool: String,
+    pub failures: u32,
+    pub error_rate: u32,
+    pub min_requests: u32,
+    pub window: Duration,
+    pub ejection: Duration,
+}
+
+impl OutlierDetection {
+    pub fn parse(args: &[&str]) -> Result<OutlierDetection, String> {
+        let pool = config::arg(args, 0)?;
+        let mut detection = OutlierDetection {
+            pool: pool.to_string(),
+            failures: 5,
+            error_rate: 50,
+            min_requests: 10,
+            window: Duration::from_secs(30),
+            ejection: Duration::from_secs(30),
+        };
+
+        for (key, value) in options(&args[1..])? {
+            match key {
+                "failures" => detection.failures = number(&[value], 0)?,
+                "error_rate" => detection.error_rate = number(&[value], 0)?,
+                "min_requests" => detection.min_requests = number(&[value], 0)?,
+                "window" => detection.window = Duration::from_secs(number(&[value], 0)?),
+                "ejection" => detection.ejection = Duration::from_secs(number(&[value], 0)?),
+                _ => return Err(format!("unknown outlier_detection option `{}`", key)),
+            }
+        }
+
+        Ok(detection)
+    }
+}
+
+#[derive(Debug)]
+struct Status {
+    /// The verdict of the active probes, up until they say otherwise.
+    probed_up: bool,
+    passes: u32,
+    fails: u32,
+    window_start: Instant,
+    requests: u32,
+    errors: u32,
+    consecutive_errors: u32,
+    ejected_until: Option<Instant>,
+    /// Why the probes last took the backend down.
+    probe_reason: String,
+    /// Why the backend was last ejected.
+    ejection_reason: String,
+}
+
+/// Whether a backend may receive traffic, as decided by probes and outlier detection.
+#[derive(Debug)]
+pub struct Health {
+    status: Mutex<Status>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            status: Mutex::new(Status {
+                probed_up: true,
+                passes: 0,
+                fails: 0,
+                window_start: Instant::now(),
+                requests: 0,
+                errors: 0,
+                consecutive_errors: 0,
+                ejected_until: None,
+                probe_reason: String::new(),
+                ejection_reason: String::new(),
+            }),
+        }
+    }
+}
+
+impl Health {
+    pub fn is_up(&self, name: &str) -> bool {
+        let mut status = self.status.lock().unwrap();
+        if let Some(until) = status.ejected_until {
+            if Instant::now() < until {
+                return false;
+            }
+            status.ejected_until = None;
+            status.consecutive_errors = 0;
+            status.ejection_reason.clear();
+            println!("health: {} ejection over\n", name);
+        }
+        status.probed_up
+    }
+
+    /// Why the backend is down, empty while it is up.
+    pub fn reason(&self) -> String {
+        let status = self.status.lock().unwrap();
+        match status.ejected_until {
+            Some(until) if Instant::now() < until => status.ejection_reason.clone(),
+            _ if !status.probed_up => status.probe_reason.clone(),
+            _ => String::new(),
+        }
+    }
+
+    /// Counts one active probe towards the rise and fall thresholds.
+    pub fn probe(&self, name: &str, check: &ActiveCheck, result: Result<(), String>) {
+        let mut status = self.status.lock().unwrap();
+        match result {
+            Ok(()) => {
+                status.passes += 1;
+                status.fails = 0;
+                if !status.probed_up && status.passes >= check.rise {
+                    status.probed_up = true;
+                    status.probe_reason.clear();
+                    println!("health: {} is up\n", name);
+                }
+            }
+            Err(reason) => {
+                status.fails += 1;
+                status.passes = 0;
+                if status.probed_up && status.fails >= check.fall {
+                    status.probed_up = false;
+                    println!("health: {} is down: {}\n", name, reason);
+                    status.probe_reason = reason;
+                }
+            }
+        }
+    }
+
+    /// Records the outcome of a proxied request, ejecting the backend when it misbehaves.
+    pub fn observe(&self, name: &str, detection: &OutlierDetection, result: Result<(), String>) {
+        let mut status = self.status.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(status.window_start) >= detection.window {
+            status.window_start = now;
+            status.requests = 0;
+            status.errors = 0;
+        }
+
+        status.requests += 1;
+        let reason = match result {
+            Ok(()) => {
+                status.consecutive_errors = 0;
+                return;
+            }
+            Err(reason) => reason,
+        };
+        status.errors += 1;
+        status.consecutive_errors += 1;
+
+        let reason = if status.consecutive_errors >= detection.failures {
+            format!("{} consecutive failures, last: {}", status.consecutive_errors, reason)
+        } else if status.requests >= detection.min_requests
+            && status.errors * 100 >= detection.error_rate * status.requests
+        {
+            format!("{} of {} requests failed", status.errors, status.requests)
+        } else {
+            return;
+        };
+
+        if status.ejected_until.is_none() {
+            println!("health: {} ejected for {:?}: {}\n", name, detection.ejection, reason);
+            status.ejected_until = Some(now + detection.ejection);
+            status.window_start = now;
+            status.requests = 0;
+            status.errors = 0;
+            status.ejection_reason = reason;
+        }
+    }
+}
+
+async fn probe_once(backend: Arc<Backend>, path: String, status: u16) -> Result<(), HttpError> {
+    let stream = await!(upstream::connect(backend.host.clone(), backend.port))?;
+    let req = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: proxylab-health\r\nConnection: close\r\n\r\n",
+        path,
+        backend.authority()
+    );
+    let (stream, _) = await!(io::write_all(stream, req).compat())
+        .map_err(|e| HttpError::BadGateway(format!("sending probe failed: {:?}", e)))?;
+
+    let resp = await!(read_response(BufReader::new(stream)))?;
+    if resp.status != status {
+        return Err(HttpError::BadGateway(format!("status {}", resp.status)));
+    }
+    Ok(())
+}
+
+/// Probes every backend of the `index`th pool forever.
+pub async fn run_checks(pools: Arc<Pools>, index: usize) {
+    let pool = &pools.pools[index];
+    let check = match pool.active.clone() {
+        Some(check) => check,
+        None => return,
+    };
+
+    loop {
+        for backend in pool.backends.iter() {
+            let probe = probe_once(backend.clone(), check.path.clone(), check.status);
+            let result = await!(with_timeout(probe, check.timeout)).map_err(|e| {
+                let info = e.info();
+                format!("probe {}: {}", info.1, info.3)
+            });
+            backend
+                .health
+                .probe(&pool.backend_name(backend), &check, result);
+        }
+        await!(sleep(check.interval));
+    }
+}
+
+/// The health of every pool member as JSON, for the admin endpoint.
+pub fn report(pools: &Pools) -> String {
+    let pools = pools
+        .pools
+        .iter()
+        .map(|pool| {
+            let backends = pool
+                .backends
+                .iter()
+                .map(|backend| {
+                    format!(
+                        "{{\"address\": {}, \"weight\": {}, \"up\": {}, \"active\": {}, \
+                         \"reason\": {}}}",
+                        errors::json_string(&backend.authority()),
+                        backend.weight,
+                        backend.health.is_up(&pool.backend_name(backend)),
+                        backend.active(),
+                        errors::json_string(&backend.health.reason())
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!(
+                "{{\"name\": {}, \"backends\": [{}]}}",
+                errors::json_string(&pool.name),
+                backends.join(", ")
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{\"pools\": [{}]}}\n", pools.join(", "))
+}
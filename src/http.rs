@@ -5,29 +5,95 @@ extern crate proxylab;
 extern crate tokio;
 
 use futures::{compat::*, prelude::*, task::SpawnExt};
-use proxylab::*;
-use std::{env::args, io::BufReader};
+use proxylab::{
+    auth::{self, Htpasswd},
+    cgi::{self, Cgi},
+    config::Config,
+    encoding,
+    errors::ErrorPages,
+    limit::{self, Limiter},
+    mime::MimeTable,
+    vhost::Site,
+    *,
+};
+use std::{
+    env::args,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    net::IpAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 use tokio::{
     fs, io,
     net::{TcpListener, TcpStream},
-    prelude::{AsyncRead, AsyncWrite},
+    prelude::AsyncRead,
 };
 
+struct State {
+    config: Config,
+    mime: MimeTable,
+    errors: Arc<ErrorPages>,
+    htpasswd: Option<Arc<Htpasswd>>,
+    limiter: Arc<Limiter>,
+}
+
 fn main() {
     let args = args().collect::<Vec<_>>();
     let port = args.get(1).and_then(|p| p.parse::<usize>().ok());
 
     if port.is_none() {
-        eprintln!("usage: {} <port>\n", args[0]);
+        eprintln!("usage: {} <port> [config]\n", args[0]);
         return;
     }
     let port = port.unwrap();
+
+    let config = match args.get(2).map(|path| Config::load(path)) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+        None => Config::default(),
+    };
+    let mime = MimeTable::from_config(&config)
+        .unwrap_or_else(|e| panic!("unable to load mime types: {:?}", e));
+    let errors = match ErrorPages::from_config(&config) {
+        Ok(errors) => Arc::new(errors),
+        Err(e) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+    };
+    if !config.writable.is_empty() && config.auth_file.is_none() {
+        eprintln!("config error: writable paths require an auth_file\n");
+        return;
+    }
+    let htpasswd = match config.auth_file.as_ref().map(|path| Htpasswd::load(path)) {
+        Some(Ok(htpasswd)) => Some(Arc::new(htpasswd)),
+        Some(Err(e)) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+        None => None,
+    };
+    let limiter = Arc::new(Limiter::new(config.limits.clone()));
+    let state = Arc::new(State {
+        config,
+        mime,
+        errors,
+        htpasswd,
+        limiter,
+    });
+
     let addr = format!("0.0.0.0:{}", port).parse().unwrap();
 
     let listener = TcpListener::bind(&addr)
         .unwrap_or_else(|e| panic!("unable to bind TCP listener on {}: {:?}", addr, e));
 
-    let server = async {
+    let server = async move {
         let mut executor = TokioDefaultSpawner;
         let mut incomings = listener
             .incoming()
@@ -35,9 +101,24 @@ fn main() {
             .map_err(|e| eprintln!("accept failed: {:?}", e));
 
         while let Some(Ok(stream)) = await!(incomings.next()) {
-            let handler = doit(stream).unwrap_or_else(|e| eprintln!("io error: {:?}", e));
+            let permit = match stream.peer_addr() {
+                Ok(client) => Limiter::accept(&state.limiter, client.ip()),
+                Err(e) => {
+                    eprintln!("io error: {:?}", e);
+                    continue;
+                }
+            };
+            let handler = match permit {
+                Ok(permit) => doit(stream, state.clone())
+                    .map(move |result| {
+                        drop(permit);
+                        result
+                    })
+                    .boxed(),
+                Err(limited) => limit::reject(stream, limited, state.errors.clone(), None).boxed(),
+            };
             let _ = executor
-                .spawn(handler)
+                .spawn(handler.unwrap_or_else(|e| eprintln!("io error: {:?}", e)))
                 .map_err(|e| eprintln!("spawn failed: {:?}", e));
         }
     };
@@ -46,68 +127,361 @@ fn main() {
     tokio::run(server);
 }
 
-async fn doit(stream: TcpStream) -> Result<(), io::Error> {
-    let (reader, writer) = stream.split();
-    let reader = BufReader::new(reader);
+async fn doit(stream: TcpStream, state: Arc<State>) -> Result<(), io::Error> {
+    let remote_addr = stream.peer_addr()?;
+    let local_port = stream.local_addr()?.port();
+    let reader = BufReader::new(stream.try_clone()?);
+    let writer = stream;
 
-    let req = await!(read_request(reader));
+    let req = await!(read_request_head(reader));
     if let Err(e) = req {
-        return await!(client_error(writer, e));
+        return await!(error_response(writer, e, state.errors.clone(), None));
     }
-    let req = req.unwrap();
+    let (reader, req) = req.unwrap();
+    let accept = req.header("Accept").map(|s| s.to_string());
 
     await!(req.log());
 
+    if let Err(limited) = state.limiter.request(remote_addr.ip()) {
+        return await!(limit::reject(writer, limited, state.errors.clone(), accept));
+    }
+
+    if req.version == "HTTP/1.1" && req.header("Host").is_none() {
+        let e = HttpError::Error("HTTP/1.1 request without Host header".to_string());
+        return await!(error_response(writer, e, state.errors.clone(), accept));
+    }
+    let site = Site::select(&state.config, &req.uri.host);
+
+    if req.method == "PUT" || req.method == "DELETE" || req.method == "MKCOL" {
+        let len = req.header("Content-Length").and_then(|l| l.parse::<u64>().ok());
+        state.limiter.transferred(remote_addr.ip(), len.unwrap_or(0));
+        return await!(serve_write(writer, reader, req, site, state));
+    }
+
+    let cgi = site.cgi_bin.as_ref().and_then(|prefix| {
+        Cgi::resolve(
+            &site.root,
+            prefix,
+            &req.uri.path,
+            remote_addr,
+            local_port,
+            state.config.cgi_timeout,
+        )
+    });
+    if let Some(cgi) = cgi {
+        return await!(serve_dynamic(writer, reader, cgi, req, remote_addr.ip(), state));
+    }
+
     if req.method != "GET" {
-        return await!(client_error(writer, HttpError::NotImplemented(req.method)));
+        let e = HttpError::NotImplemented(req.method);
+        return await!(error_response(writer, e, state.errors.clone(), accept));
+    }
+    let path = req.uri.path.split('?').next().unwrap_or_default();
+    if path.split('/').any(|segment| segment == "..") {
+        let e = HttpError::Forbidden(path.to_string());
+        return await!(error_response(writer, e, state.errors.clone(), accept));
+    }
+    let filename = site.root.clone() + path;
+
+    await!(serve_static(writer, filename, req, site, remote_addr.ip(), state))
+}
+
+async fn serve_write<R: AsyncRead + BufRead>(
+    writer: TcpStream,
+    reader: R,
+    req: Request,
+    site: Site,
+    state: Arc<State>,
+) -> Result<(), io::Error> {
+    let accept = req.header("Accept").map(|s| s.to_string());
+    let errors = state.errors.clone();
+
+    let path = req.uri.path.split('?').next().unwrap_or_default().to_string();
+    let is_writable = state.config.writable.iter().any(|prefix| {
+        path.starts_with(prefix.as_str())
+            && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+    });
+    if !is_writable || path.split('/').any(|segment| segment == "..") {
+        let e = HttpError::MethodNotAllowed(format!("{} {}", req.method, path));
+        return await!(error_response(writer, e, errors, accept));
+    }
+
+    let credentials = req.header("Authorization").and_then(auth::parse_basic);
+    let user = match (state.htpasswd.clone(), credentials) {
+        (Some(htpasswd), Some((user, password))) => {
+            // bcrypt is slow enough to stall every other connection on this reactor thread.
+            let ok = if htpasswd.is_slow(&user, &password) {
+                let user = user.clone();
+                match await!(blocking(move || htpasswd.verify(&user, &password))) {
+                    Ok(ok) => ok,
+                    Err(e) => return await!(error_response(writer, e, errors, accept)),
+                }
+            } else {
+                htpasswd.verify(&user, &password)
+            };
+            Some(user).filter(|_| ok)
+        }
+        _ => None,
+    };
+    if user.is_none() {
+        let info = HttpError::Unauthorized(path).info();
+        let mut resp = errors.render(
+            info.0,
+            &info.1,
+            &info.2,
+            &info.3,
+            accept.as_ref().map(|s| s.as_str()),
+        );
+        resp.headers.push(format!(
+            "WWW-Authenticate: Basic realm=\"{}\"",
+            state.config.auth_realm
+        ));
+        return await!(response(writer, resp));
+    }
+    let user = user.unwrap();
+
+    let filename = site.root.clone() + &path;
+    let existing = std::fs::metadata(&filename).ok();
+
+    let result = match req.method.as_str() {
+        "PUT" => {
+            let len = req.header("Content-Length").and_then(|l| l.parse::<u64>().ok());
+            let limit = state.config.max_body_size;
+            match (existing.as_ref(), len) {
+                (Some(m), _) if m.is_dir() => Err(HttpError::IsDirectory(path.clone())),
+                (_, None) => Err(HttpError::Error("PUT requires Content-Length".to_string())),
+                (_, Some(len)) if len > limit => Err(too_large(limit)),
+                (_, Some(len)) => await!(put_file(reader, filename.clone(), len))
+                    .map(|_| existing.is_none())
+                    .map_err(|e| HttpError::Internal(format!("{}: {:?}", path, e))),
+            }
+        }
+        "DELETE" => match existing.as_ref() {
+            None => Err(HttpError::NotFound(path.clone())),
+            Some(m) if m.is_dir() => await!(fs::remove_dir(filename.clone()).compat())
+                .map(|_| false)
+                .map_err(|e| HttpError::Conflict(format!("{}: {:?}", path, e))),
+            Some(_) => await!(fs::remove_file(filename.clone()).compat())
+                .map(|_| false)
+                .map_err(|e| HttpError::Internal(format!("{}: {:?}", path, e))),
+        },
+        _ => match existing {
+            Some(_) => Err(HttpError::MethodNotAllowed(format!("{} already exists", path))),
+            None => await!(fs::create_dir(filename.clone()).compat())
+                .map(|_| true)
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::NotFound => HttpError::Conflict(path.clone()),
+                    _ => HttpError::Internal(format!("{}: {:?}", path, e)),
+                }),
+        },
+    };
+
+    let created = match result {
+        Ok(created) => created,
+        Err(e) => return await!(error_response(writer, e, errors, accept)),
+    };
+    let (status, reason) = if created {
+        (201, "Created")
+    } else {
+        (204, "No Content")
+    };
+    let resp = Response {
+        version: "HTTP/1.0".to_string(),
+        status,
+        reason: reason.to_string(),
+        headers: vec!["Content-Length: 0".to_string()],
+        content: vec![],
+    };
+
+    await!(response(writer, resp))?;
+
+    println!("{} {} by {}: {}\n", req.method, filename, user, status);
+
+    Ok(())
+}
+
+/// Streams `len` bytes of the request body into a temporary file next to `filename`,
+/// then renames it into place so readers never see a partial upload.
+async fn put_file<R: AsyncRead + BufRead>(
+    reader: R,
+    filename: String,
+    len: u64,
+) -> Result<(), io::Error> {
+    static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+    let path = Path::new(&filename);
+    let tmp = path
+        .with_file_name(format!(
+            ".{}.upload-{}-{}",
+            path.file_name().and_then(|n| n.to_str()).unwrap_or_default(),
+            std::process::id(),
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        ))
+        .to_string_lossy()
+        .to_string();
+
+    let file = await!(fs::File::create(tmp.clone()).compat())?;
+    let copied = await!(io::copy(reader.take(len), file).compat());
+    let result = match copied {
+        Ok((n, _, _)) if n < len => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "request body ended early",
+        )),
+        Ok(_) => await!(fs::rename(tmp.clone(), filename).compat()),
+        Err(e) => Err(e),
+    };
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+async fn serve_dynamic<R: AsyncRead + BufRead>(
+    writer: TcpStream,
+    reader: R,
+    cgi: Cgi,
+    req: Request,
+    client: IpAddr,
+    state: Arc<State>,
+) -> Result<(), io::Error> {
+    let accept = req.header("Accept").map(|s| s.to_string());
+    if req.method != "GET" && req.method != "POST" && req.method != "HEAD" {
+        let e = HttpError::NotImplemented(req.method);
+        return await!(error_response(writer, e, state.errors.clone(), accept));
+    }
+    let is_head = req.method == "HEAD";
+    let script = cgi.script.display().to_string();
+
+    let body = match body_framing(&req.headers) {
+        Ok(framing) => await!(cgi::spool(reader, framing, state.config.max_body_size)),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = body {
+        return await!(error_response(writer, e, state.errors.clone(), accept));
+    }
+    let (_, body) = body.unwrap();
+    let uploaded = body.len;
+
+    let started = await!(cgi.start(req, body));
+    if let Err(e) = started {
+        return await!(error_response(writer, e, state.errors.clone(), accept));
     }
-    let filename = ".".to_string() + &req.uri.path;
+    let (resp, output) = started.unwrap();
 
-    await!(serve_static(writer, filename))
+    let writer = await!(response_head(writer, resp))?;
+    let size = await!(output.send(writer, is_head))?;
+    state.limiter.transferred(client, uploaded + size);
+
+    println!("cgi {} size {} served\n", script, size);
+
+    Ok(())
 }
 
-async fn serve_static(writer: impl AsyncWrite, filename: String) -> Result<(), io::Error> {
+async fn serve_static(
+    writer: TcpStream,
+    filename: String,
+    req: Request,
+    site: Site,
+    client: IpAddr,
+    state: Arc<State>,
+) -> Result<(), io::Error> {
+    let accept = req.header("Accept").map(|s| s.to_string());
+    let errors = state.errors.clone();
+
     let file = await!(fs::File::open(filename.clone()).compat());
     if let Err(e) = file {
         match e.kind() {
             io::ErrorKind::NotFound => {
-                return await!(client_error(writer, HttpError::NotFound(filename)));
+                let e = HttpError::NotFound(filename);
+                return await!(error_response(writer, e, errors, accept));
             }
             io::ErrorKind::PermissionDenied => {
-                return await!(client_error(writer, HttpError::Forbidden(filename)));
+                let e = HttpError::Forbidden(filename);
+                return await!(error_response(writer, e, errors, accept));
             }
             _ => return Err(e),
         }
     }
-    let file = file.unwrap();
-    let file_type = get_filetype(&filename);
+    let (file, metadata) = await!(file.unwrap().metadata().compat())?;
+    if metadata.is_dir() {
+        let e = HttpError::IsDirectory(filename);
+        return await!(error_response(writer, e, errors, accept));
+    }
+    let size = metadata.len();
+    let mut file = file.into_std();
 
-    let (_, content) = await!(io::read_to_end(file, vec![]).compat())?;
-    let size = content.len();
+    let mut head = vec![];
+    if state.mime.needs_sniff(&filename) {
+        (&mut file).take(512).read_to_end(&mut head)?;
+        file.seek(SeekFrom::Start(0))?;
+    }
+    let content_type = state.mime.content_type(&filename, &head);
+    let accept_encoding = req.header("Accept-Encoding");
+
+    let mut headers = vec![format!("Content-Type: {}", content_type)];
+    if site.gzip || site.gzip_static {
+        headers.push("Vary: Accept-Encoding".to_string());
+    }
+
+    let sibling = if site.gzip_static {
+        encoding::precompressed(&filename, accept_encoding)
+    } else {
+        None
+    };
+    let (file, size, coding) = match sibling {
+        Some((coding, sibling)) => {
+            let file = std::fs::File::open(&sibling)?;
+            let size = file.metadata()?.len();
+            (file, size, Some(coding))
+        }
+        None => (file, size, None),
+    };
+
+    let compress = coding.is_none()
+        && site.gzip
+        && size >= state.config.gzip_min_length
+        && encoding::is_compressible(&content_type)
+        && encoding::accepts(accept_encoding, "gzip");
+
+    if let Some(coding) = coding.as_ref() {
+        headers.push(format!("Content-Encoding: {}", coding));
+    } else if compress {
+        headers.push("Content-Encoding: gzip".to_string());
+    }
+    // A gzipped body is delimited by closing the connection instead.
+    if !compress {
+        headers.push(format!("Content-Length: {}", size));
+    }
 
     let resp = Response {
         version: "HTTP/1.0".to_string(),
         status: 200,
         reason: "OK".to_string(),
-        headers: vec![
-            format!(
-                "Content-Type: {}",
-                match file_type {
-                    FileType::Gif => "image/gif",
-                    FileType::Html => "text/html",
-                    FileType::Jpg => "image/jpg",
-                    FileType::PlainText => "text/plain",
-                    FileType::Png => "image/png",
-                }
-            ),
-            format!("Content-Length: {}", size),
-        ],
-        content,
+        headers,
+        content: vec![],
     };
 
-    await!(response(writer, resp))?;
+    // A gzipped body is charged at its uncompressed size, which is all that is known here.
+    state.limiter.transferred(client, size);
 
-    println!("file {} size {} served\n", filename, size);
+    let writer = await!(response_head(writer, resp))?;
+    if compress {
+        await!(encoding::gzip_file(file, writer, state.config.gzip_level))?;
+    } else {
+        await!(proxylab::file::send_file(writer, file, size))?;
+    }
+
+    println!(
+        "file {} size {} served{}\n",
+        filename,
+        size,
+        match coding.as_ref() {
+            Some(coding) => format!(" as {}", coding),
+            None if compress => " as gzip".to_string(),
+            None => String::new(),
+        }
+    );
 
     Ok(())
 }
@@ -0,0 +1,355 @@
+use std::{char, iter::Peekable, str::Chars};
+
+/// Arrays and objects nested deeper than this are refused rather than overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A parsed JSON document. Objects keep their members in order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// The member `key` of an object.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        offset: 0,
+        depth: 0,
+    };
+    let value = parser.value()?;
+    parser.whitespace();
+    match parser.chars.peek() {
+        None => Ok(value),
+        Some(_) => Err(parser.error("trailing characters")),
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    /// Characters consumed, for error messages.
+    offset: usize,
+    /// Arrays and objects open around the current value.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at character {}", message, self.offset)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.offset += 1;
+        self.chars.next()
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(' ') | Some('\t') | Some('\n') | Some('\r') = self.chars.peek() {
+            self.next();
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected `{}`", expected))),
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        for c in word.chars() {
+            self.expect(c)?;
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.whitespace();
+        match self.chars.peek() {
+            Some('n') => self.literal("null", Value::Null),
+            Some('t') => self.literal("true", Value::Bool(true)),
+            Some('f') => self.literal("false", Value::Bool(false)),
+            Some('"') => self.string().map(Value::String),
+            Some('[') | Some('{') => self.nested(),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn nested(&mut self) -> Result<Value, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        self.depth += 1;
+        let value = match self.chars.peek() {
+            Some('[') => self.array(),
+            _ => self.object(),
+        };
+        self.depth -= 1;
+        value
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let mut number = String::new();
+        while let Some(c) = self.chars.peek() {
+            match c {
+                '0'..='9' | '-' | '+' | '.' | 'e' | 'E' => number.push(*c),
+                _ => break,
+            }
+            self.next();
+        }
+        match number.parse() {
+            Ok(n) if is_number(&number) => Ok(Value::Number(n)),
+            _ => Err(self.error(&format!("invalid number `{}`", number))),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self
+                .next()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("invalid \\u escape"))?;
+            code = code * 16 + digit;
+        }
+        Ok(code)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => {
+                        let mut code = self.hex4()?;
+                        // A high surrogate needs its low half to make one character.
+                        if code >= 0xd800 && code < 0xdc00 {
+                            self.expect('\\')?;
+                            self.expect('u')?;
+                            let low = self.hex4()?;
+                            if low < 0xdc00 || low > 0xdfff {
+                                return Err(self.error("unpaired surrogate"));
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        } else if code >= 0xdc00 && code <= 0xdfff {
+                            return Err(self.error("unpaired surrogate"));
+                        }
+                        s.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) => s.push(c),
+                None => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = vec![];
+        self.whitespace();
+        if let Some(']') = self.chars.peek() {
+            self.next();
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut members = vec![];
+        self.whitespace();
+        if let Some('}') = self.chars.peek() {
+            self.next();
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.whitespace();
+            let key = self.string()?;
+            self.whitespace();
+            self.expect(':')?;
+            members.push((key, self.value()?));
+            self.whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(members)),
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+}
+
+/// Whether `s` follows the JSON number grammar, which is stricter than what `f64` parses:
+/// no leading `+`, no leading zeros, and digits on both sides of a `.`.
+fn is_number(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while bytes.get(*i).map_or(false, |b| b.is_ascii_digit()) {
+            *i += 1;
+        }
+        *i > start
+    };
+
+    let mut i = 0;
+    if bytes.get(i) == Some(&b'-') {
+        i += 1;
+    }
+    match bytes.get(i) {
+        Some(b'0') => i += 1,
+        Some(b'1'..=b'9') => {
+            digits(&mut i);
+        }
+        _ => return false,
+    }
+    if bytes.get(i) == Some(&b'.') {
+        i += 1;
+        if !digits(&mut i) {
+            return false;
+        }
+    }
+    if let Some(b'e') | Some(b'E') = bytes.get(i) {
+        i += 1;
+        if let Some(b'+') | Some(b'-') = bytes.get(i) {
+            i += 1;
+        }
+        if !digits(&mut i) {
+            return false;
+        }
+    }
+    i == bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn escapes() {
+        let parsed = parse(r#""a\"\\\/\b\f\n\r\t\u00e9""#);
+        assert_eq!(parsed, Ok(string("a\"\\/\u{8}\u{c}\n\r\t\u{e9}")));
+        assert!(parse(r#""\x""#).is_err());
+        assert!(parse(r#""\u12""#).is_err());
+        assert!(parse(r#""\u12g4""#).is_err());
+    }
+
+    #[test]
+    fn surrogates() {
+        assert_eq!(parse(r#""\ud83d\ude00""#), Ok(string("\u{1f600}")));
+        assert!(parse(r#""\ud800\u0041""#).is_err());
+        assert!(parse(r#""\ud800\ud800""#).is_err());
+        assert!(parse(r#""\ud800""#).is_err());
+        assert!(parse(r#""\udc00""#).is_err());
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse("0"), Ok(Value::Number(0.0)));
+        assert_eq!(parse("-12.5e2"), Ok(Value::Number(-1250.0)));
+        assert_eq!(parse("1E+2"), Ok(Value::Number(100.0)));
+        assert_eq!(parse(" 7 "), Ok(Value::Number(7.0)));
+        for invalid in &["01", "1.", "+1", "-", "1e", "1.2.3", "--1", "1e+"] {
+            assert!(parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn documents() {
+        let parsed = parse(r#"{"b": [1, true, null], "a": {"c": "d"}}"#).unwrap();
+        assert_eq!(parsed.get("a").and_then(|a| a.get("c")), Some(&string("d")));
+        match parsed {
+            Value::Object(members) => {
+                let keys = members.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+                assert_eq!(keys, ["b", "a"]);
+            }
+            _ => panic!("expected an object"),
+        }
+        assert_eq!(parse("[]"), Ok(Value::Array(vec![])));
+        assert_eq!(parse("{ }"), Ok(Value::Object(vec![])));
+    }
+
+    #[test]
+    fn malformed() {
+        let invalid = [
+            "",
+            "[1,]",
+            "[1",
+            "{\"a\" 1}",
+            "{\"a\": 1,}",
+            "{1: 2}",
+            "\"abc",
+            "nul",
+            "[] x",
+            ".5",
+        ];
+        for invalid in invalid.iter() {
+            assert!(parse(invalid).is_err(), "{} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn depth() {
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&nested(100_000)).is_err());
+        assert!(parse(&"{\"a\":".repeat(100_000)).is_err());
+    }
+}
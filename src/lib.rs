@@ -1,25 +1,73 @@
 #![feature(async_await, await_macro, futures_api, pin, try_blocks)]
 
+extern crate aho_corasick;
+extern crate base64;
+extern crate bcrypt;
+extern crate flate2;
 extern crate futures;
+extern crate libc;
+extern crate md5;
+extern crate rand;
 extern crate regex;
+extern crate sha1;
 extern crate tokio;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod acl;
+pub mod auth;
+pub mod balance;
+pub mod blocklist;
 pub mod cache;
-
+pub mod cgi;
+pub mod config;
+pub mod dns;
+pub mod encoding;
+pub mod errors;
+pub mod fault;
+pub mod file;
+pub mod har;
+pub mod happy_eyeballs;
+pub mod health;
+pub mod json;
+pub mod limit;
+pub mod mime;
+pub mod mirror;
+pub mod replay;
+pub mod retry;
+pub mod reverse;
+pub mod socks;
+pub mod throttle;
+pub mod upstream;
+pub mod vhost;
+
+use crate::errors::ErrorPages;
 use futures::{
+    channel::oneshot,
     future::ready,
     stream::iter,
     {compat::*, prelude::*},
 };
 use regex::Regex;
-use std::{io::BufRead, iter::once};
+use std::{
+    io::{BufRead, Read},
+    iter::once,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     io,
     prelude::{AsyncRead, AsyncWrite},
+    timer::{Delay, Timeout},
 };
 
+/// Largest piece of a body held in memory at once while it is copied.
+const BODY_CHUNK_SIZE: u64 = 64 * 1024;
+
 async fn log(logs: Vec<String>) {
     use tokio::prelude::stream::iter_ok;
     use tokio::prelude::{Future, IntoFuture, Stream};
@@ -40,16 +88,110 @@ pub enum HttpError {
     Forbidden(String),
     NotFound(String),
     NotImplemented(String),
+    Unauthorized(String),
+    ProxyAuthRequired(String),
+    MethodNotAllowed(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    TooManyRequests(String),
+    Internal(String),
+    BadGateway(String),
+    ServiceUnavailable(String),
+    GatewayTimeout(String),
     Error(String),
 }
 
-#[derive(Debug)]
-pub enum FileType {
-    Html,
-    Jpg,
-    Png,
-    Gif,
-    PlainText,
+impl HttpError {
+    /// Returns the status code, reason phrase, message and detail shown to the client.
+    pub fn info(self) -> (u16, String, String, String) {
+        match self {
+            HttpError::Error(e) => (400, "Error".to_string(), "Error occured".to_string(), e),
+            HttpError::Forbidden(e) => (
+                403,
+                "Forbidden".to_string(),
+                "The requested file is forbidden".to_string(),
+                e,
+            ),
+            HttpError::IsDirectory(e) => (
+                403,
+                "Forbidden".to_string(),
+                "The requested file is a directory".to_string(),
+                e,
+            ),
+            HttpError::NotFound(e) => (
+                404,
+                "NotFound".to_string(),
+                "The requested file is not found".to_string(),
+                e,
+            ),
+            HttpError::NotImplemented(e) => (
+                501,
+                "NotImplemented".to_string(),
+                "The requested method is not implemented".to_string(),
+                e,
+            ),
+            HttpError::Unauthorized(e) => (
+                401,
+                "Unauthorized".to_string(),
+                "Authentication is required".to_string(),
+                e,
+            ),
+            HttpError::ProxyAuthRequired(e) => (
+                407,
+                "ProxyAuthenticationRequired".to_string(),
+                "Proxy authentication is required".to_string(),
+                e,
+            ),
+            HttpError::MethodNotAllowed(e) => (
+                405,
+                "MethodNotAllowed".to_string(),
+                "The requested method is not allowed here".to_string(),
+                e,
+            ),
+            HttpError::Conflict(e) => (
+                409,
+                "Conflict".to_string(),
+                "The request conflicts with the current state of the resource".to_string(),
+                e,
+            ),
+            HttpError::PayloadTooLarge(e) => (
+                413,
+                "PayloadTooLarge".to_string(),
+                "The request body is larger than the server accepts".to_string(),
+                e,
+            ),
+            HttpError::TooManyRequests(e) => (
+                429,
+                "TooManyRequests".to_string(),
+                "The client is sending too much; slow down and try again later".to_string(),
+                e,
+            ),
+            HttpError::Internal(e) => (
+                500,
+                "InternalServerError".to_string(),
+                "The server failed to handle the request".to_string(),
+                e,
+            ),
+            HttpError::BadGateway(e) => (
+                502,
+                "BadGateway".to_string(),
+                "The upstream sent an invalid response".to_string(),
+                e,
+            ),
+            HttpError::ServiceUnavailable(e) => (
+                503,
+                "ServiceUnavailable".to_string(),
+                "The service is temporarily unavailable".to_string(),
+                e,
+            ),
+            HttpError::GatewayTimeout(e) => (
+                504,
+                "GatewayTimeout".to_string(),
+                "The upstream did not answer in time".to_string(),
+                e,
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -95,13 +237,27 @@ pub struct Request {
     pub uri: Uri,
     pub version: String,
     pub headers: Vec<String>,
+    pub body: Vec<u8>,
 }
 
 impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// The request target as logged: the path, or `host:port` for `CONNECT`.
+    pub fn target(&self) -> String {
+        if self.method == "CONNECT" {
+            upstream::format_authority(&self.uri.host, self.uri.port)
+        } else {
+            self.uri.path.clone()
+        }
+    }
+
     pub async fn log(&self) {
         let mut logs = vec![format!(
                 "request: {} {} {}",
-                self.method, self.uri.path, self.version
+                self.method, self.target(), self.version
             )];
         logs.append(&mut self.headers.clone());
         logs.push(String::new());
@@ -120,6 +276,10 @@ pub struct Response {
 }
 
 impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
     pub async fn log(&self) {
         let mut logs = vec![format!(
                 "response: {} {} {}",
@@ -133,18 +293,141 @@ impl Response {
     }
 }
 
-pub fn get_filetype(filename: &str) -> FileType {
-    if filename.ends_with(".html") {
-        FileType::Html
-    } else if filename.ends_with(".jpg") {
-        FileType::Jpg
-    } else if filename.ends_with(".png") {
-        FileType::Png
-    } else if filename.ends_with(".gif") {
-        FileType::Gif
-    } else {
-        FileType::PlainText
+/// Finds the value of the first header called `name`, ignoring case.
+pub fn header<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|h| {
+        let mut split = h.splitn(2, ':');
+        let key = split.next()?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            split.next().map(|v| v.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Lists the values of every header called `name`, ignoring case.
+pub fn header_values<'a>(
+    headers: &'a [String],
+    name: &'a str,
+) -> impl Iterator<Item = &'a str> + 'a {
+    headers.iter().filter_map(move |h| {
+        let mut split = h.splitn(2, ':');
+        let key = split.next()?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            split.next().map(|v| v.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether the last transfer coding in `headers` is chunked, which is then what delimits
+/// the body.
+pub fn is_chunked(headers: &[String]) -> bool {
+    header_values(headers, "Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .last()
+        .map_or(false, |coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Returns `headers` minus every header called `name`, ignoring case.
+pub fn without_header(headers: &[String], name: &str) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|h| {
+            let key = h.splitn(2, ':').next().unwrap_or_default();
+            !key.trim().eq_ignore_ascii_case(name)
+        })
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// Threads running `blocking` work.
+const BLOCKING_THREADS: usize = 8;
+/// Jobs waiting for a `blocking` thread before further ones are refused.
+const BLOCKING_QUEUE: usize = 64;
+
+type Job = Box<dyn FnMut() + Send>;
+
+lazy_static! {
+    static ref BLOCKING: Mutex<SyncSender<Job>> = Mutex::new(start_blocking_pool());
+}
+
+fn start_blocking_pool() -> SyncSender<Job> {
+    let (tx, rx) = mpsc::sync_channel::<Job>(BLOCKING_QUEUE);
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..BLOCKING_THREADS {
+        let rx = rx.clone();
+        std::thread::spawn(move || loop {
+            let mut job = match rx.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            // A panicking job drops its sender, which its caller sees; the thread goes on.
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| job()));
+        });
     }
+    tx
+}
+
+/// Runs `f` on a pool thread so that blocking work does not stall the reactor. Fails with
+/// 503 rather than queue without bound when the pool is behind.
+pub async fn blocking<T, F>(f: F) -> Result<T, HttpError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    let mut work = Some((f, tx));
+    let job: Job = Box::new(move || {
+        if let Some((f, tx)) = work.take() {
+            let _ = tx.send(f());
+        }
+    });
+    BLOCKING.lock().unwrap().try_send(job).map_err(|_| {
+        HttpError::ServiceUnavailable("too much blocking work queued".to_string())
+    })?;
+    await!(rx).map_err(|_| HttpError::Internal("blocking task panicked".to_string()))
+}
+
+/// Waits until `reader` has data buffered, failing if the peer closes or resets first.
+pub async fn first_byte<R: BufRead + Send + 'static>(reader: R) -> Result<R, io::Error> {
+    use tokio::prelude::{future::poll_fn, Async};
+
+    let mut reader = Some(reader);
+    await!(poll_fn(move || {
+        let filled = reader.as_mut().unwrap().fill_buf().map(|buf| buf.len());
+        match filled {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(Async::Ready(reader.take().unwrap())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    })
+    .compat())
+}
+
+/// Waits for `duration` without blocking the reactor.
+pub async fn sleep(duration: Duration) {
+    let _ = await!(Delay::new(Instant::now() + duration).compat());
+}
+
+/// Runs `fut`, giving up with a gateway timeout once `duration` has passed.
+pub async fn with_timeout<T, F>(fut: F, duration: Duration) -> Result<T, HttpError>
+where
+    T: Send + 'static,
+    F: Future<Output = Result<T, HttpError>> + Send + 'static,
+{
+    let timeout = Timeout::new(fut.boxed().compat(), duration);
+    await!(timeout.compat()).map_err(|e| {
+        if e.is_elapsed() {
+            HttpError::GatewayTimeout(format!("no answer within {:?}", duration))
+        } else {
+            e.into_inner()
+                .unwrap_or_else(|| HttpError::Internal("timer failed".to_string()))
+        }
+    })
 }
 
 pub async fn print_requesthdrs(reader: impl AsyncRead + BufRead) {
@@ -168,6 +451,7 @@ pub fn parse_uri(uri: &str, default_host: &str) -> Option<Uri> {
         let host = caps
             .get(1)
             .map(|s| s.as_str())
+            .filter(|s| !s.is_empty())
             .unwrap_or_else(|| default_host);
         let mut split = host.split(':');
 
@@ -187,52 +471,26 @@ pub fn parse_uri(uri: &str, default_host: &str) -> Option<Uri> {
 }
 
 pub async fn client_error(writer: impl AsyncWrite, e: HttpError) -> Result<(), io::Error> {
-    let info: (u16, String, String, String) = match e {
-        HttpError::Error(e) => (400, "Error".to_string(), "Error occured".to_string(), e),
-        HttpError::Forbidden(e) => (
-            403,
-            "Forbidden".to_string(),
-            "The requested file is forbidden".to_string(),
-            e,
-        ),
-        HttpError::IsDirectory(e) => (
-            403,
-            "Forbidden".to_string(),
-            "The requested file is a directory".to_string(),
-            e,
-        ),
-        HttpError::NotFound(e) => (
-            404,
-            "NotFound".to_string(),
-            "The requested file is not found".to_string(),
-            e,
-        ),
-        HttpError::NotImplemented(e) => (
-            501,
-            "NotImplemented".to_string(),
-            "The requested method is not implemented".to_string(),
-            e,
-        ),
-    };
-
-    let body = format!(
-        "<html><head><title>Mini Error</title></head><body bgcolor=ffffff>\r\n\
-         <b>{}: {}</b>\r\n\
-         <p>{}: {}\r\n\
-         <hr><em>Mini Web server</em></body></html>\r\n",
-        info.0, info.1, info.2, info.3,
-    );
-
-    let line = format!("HTTP/1.0 {} {}\r\n", info.0, info.1);
+    await!(error_response(writer, e, Arc::new(ErrorPages::default()), None))
+}
 
-    let header = format!(
-        "Content-Type: text/html\r\nContent-Length: {}\r\n\r\n",
-        body.len()
+/// Sends `e` rendered with `pages`, choosing the body format from the client's `accept`.
+pub async fn error_response(
+    writer: impl AsyncWrite,
+    e: HttpError,
+    pages: Arc<ErrorPages>,
+    accept: Option<String>,
+) -> Result<(), io::Error> {
+    let info = e.info();
+    let resp = pages.render(
+        info.0,
+        &info.1,
+        &info.2,
+        &info.3,
+        accept.as_ref().map(|s| s.as_str()),
     );
 
-    let (writer, _) = await!(io::write_all(writer, line).compat())?;
-    let (writer, _) = await!(io::write_all(writer, header).compat())?;
-    let _ = await!(io::write_all(writer, body).compat())?;
+    await!(response(writer, resp))?;
 
     println!(
         "client error: {} {}\n{}: {}\n",
@@ -261,7 +519,10 @@ async fn read_headers(
     }
 }
 
-pub async fn read_request(reader: impl AsyncRead + BufRead) -> Result<Request, HttpError> {
+/// Reads the request line and headers, handing back the reader positioned at the body.
+pub async fn read_request_head<R: AsyncRead + BufRead>(
+    reader: R,
+) -> Result<(impl AsyncRead + BufRead, Request), HttpError> {
     let (reader, buf) = await!(io::read_until(reader, b'\n', vec![]).compat())
         .map_err(|e| HttpError::Error(format!("read failed: {:?}", e)))?;
 
@@ -279,26 +540,285 @@ pub async fn read_request(reader: impl AsyncRead + BufRead) -> Result<Request, H
         })
         .ok_or_else(|| HttpError::Error("request line parsing failed".to_string()))?;
 
-    let (_, headers) = await!(read_headers(reader))?;
-    let mut request_host = String::new();
-    for header in headers.iter() {
-        if header.starts_with("Host:") {
-            request_host = header
-                .split_whitespace()
-                .nth(1)
-                .map(|s| s.to_string())
-                .ok_or_else(|| HttpError::Error("parse host failed".to_string()))?;
+    let (reader, headers) = await!(read_headers(reader))?;
+    check_framing(&headers)?;
+    let request_host = header(&headers, "Host").unwrap_or_default().to_string();
+    // CONNECT names its target in authority form, `host:port`.
+    let uri = if method == "CONNECT" {
+        upstream::parse_authority(&uri).ok().map(|(host, port)| Uri {
+            host,
+            port,
+            path: String::new(),
+        })
+    } else {
+        parse_uri(&uri, &request_host)
+    }
+    .ok_or_else(|| HttpError::Error("parse uri failed".to_string()))?;
+
+    Ok((
+        reader,
+        Request {
+            method,
+            uri,
+            version,
+            headers,
+            body: vec![],
+        },
+    ))
+}
+
+/// Refuses a request whose body could be delimited differently by another server on the
+/// way, which would let the client smuggle a second request inside it.
+fn check_framing(headers: &[String]) -> Result<(), HttpError> {
+    let mut lengths = header_values(headers, "Content-Length");
+    let length = lengths.next();
+    if lengths.any(|other| Some(other) != length) {
+        return Err(HttpError::Error("conflicting Content-Length headers".to_string()));
+    }
+
+    if let Some(coding) = header(headers, "Transfer-Encoding") {
+        if length.is_some() {
+            return Err(HttpError::Error("both Content-Length and Transfer-Encoding".to_string()));
+        }
+        if !is_chunked(headers) {
+            return Err(HttpError::Error(format!(
+                "Transfer-Encoding `{}` does not end in chunked",
+                coding
+            )));
         }
     }
-    let uri = parse_uri(&uri, &request_host)
-        .ok_or_else(|| HttpError::Error("parse uri failed".to_string()))?;
+    Ok(())
+}
 
-    Ok(Request {
-        method,
-        uri,
-        version,
-        headers,
-    })
+pub async fn read_request(
+    reader: impl AsyncRead + BufRead,
+    limit: u64,
+) -> Result<Request, HttpError> {
+    let (reader, req) = await!(read_request_head(reader))?;
+    let (_, req) = await!(read_request_body(reader, req, limit))?;
+    Ok(req)
+}
+
+/// Reads the body following a head from `read_request_head` into `req`, refusing one of
+/// more than `limit` bytes.
+pub async fn read_request_body<R: AsyncRead + BufRead>(
+    reader: R,
+    req: Request,
+    limit: u64,
+) -> Result<(R, Request), HttpError> {
+    let (reader, body) = await!(read_body(reader, req.headers.clone(), limit))?;
+
+    // The body is forwarded de-chunked, so its framing headers must say so.
+    let headers = if is_chunked(&req.headers) {
+        without_header(&req.headers, "Transfer-Encoding")
+            .into_iter()
+            .chain(once(format!("Content-Length: {}", body.len())))
+            .collect()
+    } else {
+        req.headers.clone()
+    };
+
+    Ok((
+        reader,
+        Request {
+            headers,
+            body,
+            ..req
+        },
+    ))
+}
+
+/// How a message body is delimited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// Exactly this many bytes; a message without framing headers has none.
+    Length(u64),
+    Chunked,
+}
+
+/// Finds how the body following `headers` is delimited.
+pub fn body_framing(headers: &[String]) -> Result<Framing, HttpError> {
+    if is_chunked(headers) {
+        return Ok(Framing::Chunked);
+    }
+    match header(headers, "Content-Length") {
+        Some(len) => parse_size(len, 10)
+            .map(Framing::Length)
+            .ok_or_else(|| HttpError::Error("parse content length failed".to_string())),
+        None => Ok(Framing::Length(0)),
+    }
+}
+
+/// Parses a length as HTTP writes it: digits only, without the sign `from_str_radix`
+/// would take, so every server on the way reads the same number.
+fn parse_size(s: &str, radix: u32) -> Option<u64> {
+    if s.is_empty() || !s.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    u64::from_str_radix(s, radix).ok()
+}
+
+/// The error for a body longer than `limit`.
+pub fn too_large(limit: u64) -> HttpError {
+    HttpError::PayloadTooLarge(format!("the body exceeds {} bytes", limit))
+}
+
+/// Appends `len` bytes from `reader` to `buf`, which only grows as they arrive.
+async fn read_exactly<R: AsyncRead>(
+    reader: R,
+    len: u64,
+    buf: Vec<u8>,
+) -> Result<(R, Vec<u8>), HttpError> {
+    let start = buf.len();
+    let (reader, buf) = await!(io::read_to_end(reader.take(len), buf).compat())
+        .map_err(|e| HttpError::Error(format!("content reading failed: {:?}", e)))?;
+    if ((buf.len() - start) as u64) < len {
+        return Err(HttpError::Error("content ended early".to_string()));
+    }
+    Ok((reader.into_inner(), buf))
+}
+
+/// Reads the size line starting a chunk.
+async fn read_chunk_size<R: AsyncRead + BufRead>(reader: R) -> Result<(R, u64), HttpError> {
+    let (reader, line) = await!(io::read_until(reader, b'\n', vec![]).compat())
+        .map_err(|e| HttpError::Error(format!("chunk size reading failed: {:?}", e)))?;
+    let line = String::from_utf8_lossy(&line);
+    // Chunk extensions after `;` carry nothing we use.
+    let size = line.split(';').next().unwrap_or_default().trim();
+    let size = parse_size(size, 16)
+        .ok_or_else(|| HttpError::Error(format!("chunk size parsing failed: `{}`", size)))?;
+    Ok((reader, size))
+}
+
+/// Skips the line ending a chunk, or the trailers and blank line ending the last one.
+async fn skip_chunk_end<R: AsyncRead + BufRead>(reader: R, last: bool) -> Result<R, HttpError> {
+    let mut reader = reader;
+    loop {
+        let (r, line) = await!(io::read_until(reader, b'\n', vec![]).compat())
+            .map_err(|e| HttpError::Error(format!("chunk footer reading failed: {:?}", e)))?;
+        reader = r;
+        if line.is_empty() {
+            return Err(HttpError::Error("content ended early".to_string()));
+        }
+        if line == b"\r\n" || line == b"\n" {
+            return Ok(reader);
+        }
+        // Anything else right after the data means the chunk was longer than its size.
+        if !last || !line.contains(&b':') {
+            return Err(HttpError::Error("malformed chunk ending".to_string()));
+        }
+    }
+}
+
+/// Reads a message body framed by either `Content-Length` or chunked encoding, refusing
+/// one of more than `limit` bytes.
+pub async fn read_body<R: AsyncRead + BufRead>(
+    reader: R,
+    headers: Vec<String>,
+    limit: u64,
+) -> Result<(R, Vec<u8>), HttpError> {
+    match body_framing(&headers)? {
+        Framing::Length(len) if len > limit => Err(too_large(limit)),
+        Framing::Length(len) => await!(read_exactly(reader, len, vec![])),
+        Framing::Chunked => {
+            let mut content = Vec::new();
+            let mut reader = reader;
+            loop {
+                let (r, size) = await!(read_chunk_size(reader))?;
+                if size == 0 {
+                    let r = await!(skip_chunk_end(r, true))?;
+                    return Ok((r, content));
+                }
+                if size > limit.saturating_sub(content.len() as u64) {
+                    return Err(too_large(limit));
+                }
+                let (r, c) = await!(read_exactly(r, size, content))?;
+                reader = await!(skip_chunk_end(r, false))?;
+                content = c;
+            }
+        }
+    }
+}
+
+/// Copies `len` bytes from `reader` to `writer`, one piece at a time.
+async fn copy_exactly<R: AsyncRead, W: AsyncWrite>(
+    reader: R,
+    writer: W,
+    len: u64,
+) -> Result<(R, W), HttpError> {
+    let mut reader = reader;
+    let mut writer = writer;
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let buf = vec![0; remaining.min(BODY_CHUNK_SIZE) as usize];
+        let (r, mut buf, n) = await!(io::read(reader, buf).compat())
+            .map_err(|e| HttpError::Error(format!("content reading failed: {:?}", e)))?;
+        if n == 0 {
+            return Err(HttpError::Error("content ended early".to_string()));
+        }
+        buf.truncate(n);
+        let (w, _) = await!(io::write_all(writer, buf).compat())
+            .map_err(|e| HttpError::BadGateway(format!("sending body failed: {:?}", e)))?;
+        reader = r;
+        writer = w;
+        remaining -= n as u64;
+    }
+
+    Ok((reader, writer))
+}
+
+async fn write_framing<W: AsyncWrite>(writer: W, framing: String) -> Result<W, HttpError> {
+    await!(io::write_all(writer, framing).compat())
+        .map(|(writer, _)| writer)
+        .map_err(|e| HttpError::BadGateway(format!("sending body failed: {:?}", e)))
+}
+
+/// Copies a body framed by `framing` from `reader` to `writer` a piece at a time, keeping
+/// the chunked framing if `rechunk` is set and dropping it otherwise, and returns how many
+/// body bytes were copied. Fails once the body proves longer than `limit`.
+pub async fn copy_body<R: AsyncRead + BufRead, W: AsyncWrite>(
+    reader: R,
+    writer: W,
+    framing: Framing,
+    limit: u64,
+    rechunk: bool,
+) -> Result<(R, W, u64), HttpError> {
+    let len = match framing {
+        Framing::Length(len) if len > limit => return Err(too_large(limit)),
+        Framing::Length(len) => len,
+        Framing::Chunked => {
+            let mut reader = reader;
+            let mut writer = writer;
+            let mut copied = 0;
+            loop {
+                let (r, size) = await!(read_chunk_size(reader))?;
+                if copied + size > limit {
+                    return Err(too_large(limit));
+                }
+                if rechunk {
+                    writer = await!(write_framing(writer, format!("{:x}\r\n", size)))?;
+                }
+                if size == 0 {
+                    let r = await!(skip_chunk_end(r, true))?;
+                    if rechunk {
+                        writer = await!(write_framing(writer, "\r\n".to_string()))?;
+                    }
+                    return Ok((r, writer, copied));
+                }
+                let (r, w) = await!(copy_exactly(r, writer, size))?;
+                reader = await!(skip_chunk_end(r, false))?;
+                writer = w;
+                if rechunk {
+                    writer = await!(write_framing(writer, "\r\n".to_string()))?;
+                }
+                copied += size;
+            }
+        }
+    };
+
+    let (reader, writer) = await!(copy_exactly(reader, writer, len))?;
+    Ok((reader, writer, len))
 }
 
 pub async fn read_response(reader: impl AsyncRead + BufRead) -> Result<Response, HttpError> {
@@ -319,47 +839,8 @@ pub async fn read_response(reader: impl AsyncRead + BufRead) -> Result<Response,
 
     let (reader, headers) = await!(read_headers(reader))?;
 
-    let mut content_length = 0usize;
-    let mut is_chunked = false;
-    for header in headers.iter() {
-        if header.starts_with("Content-Length:") {
-            is_chunked = false;
-            content_length = header
-                .split_whitespace()
-                .nth(1)
-                .and_then(|s| s.parse().ok())
-                .ok_or_else(|| HttpError::Error("parse content length failed".to_string()))?;
-        } else if header.starts_with("Transfer-Encoding:") && header.contains("chunked") {
-            is_chunked = true;
-            content_length = 0;
-        }
-    }
-
-    let content = if is_chunked {
-        let mut content = Vec::new();
-        let mut reader = reader;
-        loop {
-            let (r, chunk_size) = await!(io::read_until(reader, b'\n', vec![]).compat())
-                .map_err(|e| HttpError::Error(format!("chunk size reading failed: {:?}", e)))?;
-            let chunk_size = String::from_utf8_lossy(&chunk_size);
-            let chunk_size = usize::from_str_radix(chunk_size.trim(), 16)
-                .map_err(|e| HttpError::Error(format!("chunk size parsing failed: {:?}", e)))?;
-            if chunk_size == 0 {
-                break;
-            }
-            let (r, mut chunk) = await!(io::read_exact(r, vec![0; chunk_size]).compat())
-                .map_err(|e| HttpError::Error(format!("chunk reading failed: {:?}", e)))?;
-            content.append(&mut chunk);
-            let (r, _) = await!(io::read_until(r, b'\n', vec![]).compat())
-                .map_err(|e| HttpError::Error(format!("chunk footer reading failed: {:?}", e)))?;
-            reader = r;
-        }
-        content
-    } else {
-        await!(io::read_exact(reader, vec![0; content_length]).compat())
-            .map(|(_, c)| c)
-            .map_err(|e| HttpError::Error(format!("content reading failed: {:?}", e)))?
-    };
+    // Nothing bounds a response body but the memory it takes.
+    let (_, content) = await!(read_body(reader, headers.clone(), u64::max_value()))?;
 
     Ok(Response {
         version,
@@ -370,8 +851,10 @@ pub async fn read_response(reader: impl AsyncRead + BufRead) -> Result<Response,
     })
 }
 
-pub async fn request(writer: impl AsyncWrite + Send, req: Request) -> Result<(), HttpError> {
+/// Sends `req`, handing back the writer so that a body streamed after it can follow.
+pub async fn request<W: AsyncWrite + Send>(writer: W, req: Request) -> Result<W, HttpError> {
     let req_line = format!("{} {} {}\r\n", req.method, req.uri.to_string(), req.version);
+    let body = req.body;
 
     let fut = io::write_all(writer, req_line).compat();
 
@@ -394,12 +877,18 @@ pub async fn request(writer: impl AsyncWrite + Send, req: Request) -> Result<(),
                 )
         })
         .map_err(|e| HttpError::Error(format!("sending failed: {:?}", e)));
-    await!(fut)?;
+    let writer = await!(fut)?;
 
-    Ok(())
+    if body.is_empty() {
+        return Ok(writer);
+    }
+    await!(io::write_all(writer, body).compat())
+        .map(|(writer, _)| writer)
+        .map_err(|e| HttpError::Error(format!("sending body failed: {:?}", e)))
 }
 
-pub async fn response(writer: impl AsyncWrite, resp: Response) -> Result<(), io::Error> {
+/// Writes the status line and headers of `resp`, leaving the body to the caller.
+pub async fn response_head<W: AsyncWrite>(writer: W, resp: Response) -> Result<W, io::Error> {
     let line = format!("{} {} {}\r\n", resp.version, resp.status, resp.reason);
 
     let (writer, _) = await!(io::write_all(writer, line).compat())?;
@@ -410,7 +899,108 @@ pub async fn response(writer: impl AsyncWrite, resp: Response) -> Result<(), io:
         writer = w;
     }
     let (writer, _) = await!(io::write_all(writer, b"\r\n").compat())?;
-    let _ = await!(io::write_all(writer, resp.content).compat())?;
+
+    Ok(writer)
+}
+
+pub async fn response(writer: impl AsyncWrite, resp: Response) -> Result<(), io::Error> {
+    let mut resp = resp;
+    let content = std::mem::replace(&mut resp.content, vec![]);
+
+    let writer = await!(response_head(writer, resp))?;
+    let _ = await!(io::write_all(writer, content).compat())?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::io::Cursor;
+
+    fn headers(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    fn body(framing: &str, raw: &str, limit: u64) -> Result<Vec<u8>, HttpError> {
+        let reader = Cursor::new(raw.as_bytes().to_vec());
+        block_on(read_body(reader, headers(&[framing]), limit)).map(|(_, body)| body)
+    }
+
+    #[test]
+    fn framing_conflicts() {
+        assert!(check_framing(&headers(&["Content-Length: 5"])).is_ok());
+        assert!(check_framing(&headers(&["Content-Length: 5", "content-length: 5"])).is_ok());
+        assert!(check_framing(&headers(&["Content-Length: 5", "Content-Length: 6"])).is_err());
+        assert!(check_framing(&headers(&["Content-Length: 5", "Content-Length: 05"])).is_err());
+        assert!(check_framing(&headers(&["Transfer-Encoding: chunked"])).is_ok());
+        assert!(check_framing(&headers(&["Transfer-Encoding: gzip, Chunked"])).is_ok());
+        assert!(check_framing(&headers(&["Transfer-Encoding: chunked, gzip"])).is_err());
+        assert!(check_framing(&headers(&["Transfer-Encoding: identity"])).is_err());
+        let both = headers(&["Content-Length: 5", "transfer-encoding: chunked"]);
+        assert!(check_framing(&both).is_err());
+        let both = headers(&["TRANSFER-ENCODING: chunked", "content-length: 0"]);
+        assert!(check_framing(&both).is_err());
+    }
+
+    #[test]
+    fn request_head_refuses_ambiguous_framing() {
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\
+                   Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let read = block_on(read_request_head(Cursor::new(raw.as_bytes().to_vec())));
+        assert!(read.is_err());
+
+        let raw = "POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nbody";
+        let read = block_on(read_request_head(Cursor::new(raw.as_bytes().to_vec())));
+        assert_eq!(read.ok().map(|(_, req)| req.uri.host), Some("a".to_string()));
+    }
+
+    #[test]
+    fn content_length() {
+        assert_eq!(body("Content-Length: 4", "bodymore", 100).ok(), Some(b"body".to_vec()));
+        assert!(body("Content-Length: 10", "short", 100).is_err());
+        assert!(body("Content-Length: +4", "body", 100).is_err());
+        assert!(body("Content-Length: -4", "body", 100).is_err());
+        assert!(body("Content-Length: 4x", "body", 100).is_err());
+        assert!(body("Content-Length: 99999999999999999999999", "body", 100).is_err());
+        match body("Content-Length: 101", "body", 100) {
+            Err(HttpError::PayloadTooLarge(_)) => {}
+            other => panic!("expected 413, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunked() {
+        let te = "Transfer-Encoding: chunked";
+        let raw = "4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n";
+        assert_eq!(body(te, raw, 100).ok(), Some(b"Wikipedia".to_vec()));
+        let raw = "A\r\n0123456789\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\n";
+        assert_eq!(body(te, raw, 100).ok(), Some(b"0123456789".to_vec()));
+        match body(te, "8\r\n01234567\r\n0\r\n\r\n", 4) {
+            Err(HttpError::PayloadTooLarge(_)) => {}
+            other => panic!("expected 413, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunk_size_overflow() {
+        let te = "Transfer-Encoding: chunked";
+        assert!(body(te, "4\r\nWiki\r\nffffffffffffffff\r\nx\r\n0\r\n\r\n", 100).is_err());
+        assert!(body(te, "10000000000000000\r\nx\r\n0\r\n\r\n", 100).is_err());
+        assert!(body(te, "+4\r\nWiki\r\n0\r\n\r\n", 100).is_err());
+        assert!(body(te, "0x4\r\nWiki\r\n0\r\n\r\n", 100).is_err());
+        assert!(body(te, "\r\nWiki\r\n0\r\n\r\n", 100).is_err());
+    }
+
+    #[test]
+    fn chunk_endings() {
+        let te = "Transfer-Encoding: chunked";
+        // Data longer than the chunk size must not be skipped over.
+        assert!(body(te, "4\r\nWikipedia\r\n0\r\n\r\n", 100).is_err());
+        assert!(body(te, "4\r\nWiki", 100).is_err());
+        assert!(body(te, "4\r\nWiki\r\n0\r\n", 100).is_err());
+        assert!(body(te, "4\r\nWiki\r\n0\r\nnot a trailer\r\n\r\n", 100).is_err());
+        assert!(body(te, "4\r\nWiki\r\n0\r\nX-Trailer: 1\r\n", 100).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+use crate::{config::number, errors::ErrorPages, response, HttpError};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{io, prelude::AsyncWrite};
+
+/// Clients tracked before idle ones are forgotten.
+const MAX_TRACKED: usize = 4096;
+
+/// How long a client turned away for too many connections is told to wait.
+const CONNECTION_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// A `rate burst` pair, as in `rate_limit 10 20`; the burst defaults to the rate.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    pub fn parse(args: &[&str]) -> Result<Rate, String> {
+        let per_second = number::<f64>(args, 0)?;
+        let burst = match args.get(1) {
+            Some(_) => number::<f64>(args, 1)?,
+            None => per_second,
+        };
+        if per_second <= 0.0 || burst < 1.0 {
+            return Err("rate and burst must be positive".to_string());
+        }
+        Ok(Rate { per_second, burst })
+    }
+}
+
+/// The `rate_limit`, `byte_rate_limit`, `user_rate_limit`, `max_connections` and
+/// `max_connections_per_client` settings; each is off unless configured.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// Requests per client IP.
+    pub requests: Option<Rate>,
+    /// Request and response body bytes per client IP.
+    pub bytes: Option<Rate>,
+    /// Requests per authenticated proxy user.
+    pub user_requests: Option<Rate>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_client: Option<usize>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate.burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate.per_second).min(self.rate.burst);
+        self.updated = now;
+    }
+
+    /// How long until the bucket holds `n` tokens.
+    fn wait(&self, n: f64) -> Duration {
+        let seconds = ((n - self.tokens) / self.rate.per_second).max(0.0);
+        Duration::from_millis((seconds * 1000.0).ceil() as u64)
+    }
+
+    /// Takes a token, or says how long until one is available.
+    fn take(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.wait(1.0))
+        }
+    }
+
+    /// Fails while a previous `charge` left the bucket in debt.
+    fn check(&mut self) -> Result<(), Duration> {
+        self.refill();
+        if self.tokens >= 0.0 {
+            Ok(())
+        } else {
+            Err(self.wait(0.0))
+        }
+    }
+
+    /// Spends `n` tokens after the fact, possibly going into debt.
+    fn charge(&mut self, n: f64) {
+        self.refill();
+        self.tokens -= n;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.rate.burst
+    }
+}
+
+#[derive(Debug, Default)]
+struct Client {
+    connections: usize,
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+/// Why a connection or request was turned away, and when to come back.
+#[derive(Debug)]
+pub enum Limited {
+    /// Too many requests or bytes: `429 Too Many Requests`.
+    Rate(Duration),
+    /// Too many connections: `503 Service Unavailable`.
+    Connections(Duration),
+}
+
+/// Per-client and per-user limits shared by every connection of one server.
+#[derive(Debug)]
+pub struct Limiter {
+    settings: Settings,
+    connections: AtomicUsize,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+    users: Mutex<HashMap<String, TokenBucket>>,
+}
+
+/// Holds one connection slot until dropped.
+#[derive(Debug)]
+pub struct Permit {
+    limiter: Arc<Limiter>,
+    client: IpAddr,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.connections.fetch_sub(1, Ordering::Relaxed);
+        if let Some(client) = self.limiter.clients.lock().unwrap().get_mut(&self.client) {
+            client.connections -= 1;
+        }
+    }
+}
+
+impl Limiter {
+    pub fn new(settings: Settings) -> Limiter {
+        Limiter {
+            settings,
+            connections: AtomicUsize::new(0),
+            clients: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Admits a new connection from `client` if both connection caps allow it.
+    pub fn accept(limiter: &Arc<Limiter>, client: IpAddr) -> Result<Permit, Limited> {
+        let mut clients = limiter.clients.lock().unwrap();
+        if clients.len() >= MAX_TRACKED {
+            clients.retain(|_, c| {
+                c.connections > 0
+                    || !c.requests.as_mut().map_or(true, |b| b.is_full())
+                    || !c.bytes.as_mut().map_or(true, |b| b.is_full())
+            });
+        }
+
+        let total = limiter.connections.load(Ordering::Relaxed);
+        let client_state = clients.entry(client).or_insert_with(Client::default);
+        let over_total = limiter.settings.max_connections.map_or(false, |max| total >= max);
+        let over_client = limiter
+            .settings
+            .max_connections_per_client
+            .map_or(false, |max| client_state.connections >= max);
+        if over_total || over_client {
+            return Err(Limited::Connections(CONNECTION_RETRY_AFTER));
+        }
+
+        client_state.connections += 1;
+        limiter.connections.fetch_add(1, Ordering::Relaxed);
+        Ok(Permit {
+            limiter: limiter.clone(),
+            client,
+        })
+    }
+
+    /// Counts one request from `client` against its request and byte budgets.
+    pub fn request(&self, client: IpAddr) -> Result<(), Limited> {
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.entry(client).or_insert_with(Client::default);
+
+        if let Some(rate) = self.settings.bytes {
+            let bucket = client.bytes.get_or_insert_with(|| TokenBucket::new(rate));
+            bucket.check().map_err(Limited::Rate)?;
+        }
+        if let Some(rate) = self.settings.requests {
+            let bucket = client.requests.get_or_insert_with(|| TokenBucket::new(rate));
+            bucket.take().map_err(Limited::Rate)?;
+        }
+        Ok(())
+    }
+
+    /// Counts one request from an authenticated proxy user.
+    pub fn user_request(&self, user: &str) -> Result<(), Limited> {
+        let rate = match self.settings.user_requests {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+        let mut users = self.users.lock().unwrap();
+        if users.len() >= MAX_TRACKED {
+            users.retain(|_, bucket| !bucket.is_full());
+        }
+        users
+            .entry(user.to_string())
+            .or_insert_with(|| TokenBucket::new(rate))
+            .take()
+            .map_err(Limited::Rate)
+    }
+
+    /// Charges bytes already transferred for `client`; later requests wait off the debt.
+    pub fn transferred(&self, client: IpAddr, bytes: u64) {
+        if let Some(rate) = self.settings.bytes {
+            let mut clients = self.clients.lock().unwrap();
+            let client = clients.entry(client).or_insert_with(Client::default);
+            client
+                .bytes
+                .get_or_insert_with(|| TokenBucket::new(rate))
+                .charge(bytes as f64);
+        }
+    }
+}
+
+/// Sends the error for `limited` with a `Retry-After` header.
+pub async fn reject(
+    writer: impl AsyncWrite,
+    limited: Limited,
+    pages: Arc<ErrorPages>,
+    accept: Option<String>,
+) -> Result<(), io::Error> {
+    let after = match limited {
+        Limited::Rate(after) | Limited::Connections(after) => after,
+    };
+    // Retry-After counts whole seconds; round up so clients do not come back early.
+    let seconds = (after.as_secs() + if after.subsec_nanos() > 0 { 1 } else { 0 }).max(1);
+    let detail = format!("retry after {} seconds", seconds);
+    let e = match limited {
+        Limited::Rate(_) => HttpError::TooManyRequests(detail),
+        Limited::Connections(_) => HttpError::ServiceUnavailable(detail),
+    };
+
+    let info = e.info();
+    let mut resp = pages.render(
+        info.0,
+        &info.1,
+        &info.2,
+        &info.3,
+        accept.as_ref().map(|s| s.as_str()),
+    );
+    resp.headers.push(format!("Retry-After: {}", seconds));
+    println!("limited: {} {}\n", info.0, info.3);
+    await!(response(writer, resp))
+}
@@ -0,0 +1,172 @@
+use std::{collections::HashMap, fs, io};
+
+const BUILTIN_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "application/javascript"),
+    ("mjs", "application/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("png", "image/png"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+    ("wasm", "application/wasm"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Magic bytes checked by `sniff`, in order.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"\x00asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"ID3", "audio/mpeg"),
+    (b"OggS", "audio/ogg"),
+];
+
+/// Maps file extensions to MIME types.
+#[derive(Debug, Clone)]
+pub struct MimeTable {
+    types: HashMap<String, String>,
+    sniff: bool,
+    default_type: String,
+    charset: Option<String>,
+}
+
+impl MimeTable {
+    /// Creates a table with the builtin types, falling back to `text/plain`.
+    pub fn new() -> Self {
+        MimeTable {
+            types: BUILTIN_TYPES
+                .iter()
+                .map(|(ext, mime)| (ext.to_string(), mime.to_string()))
+                .collect(),
+            sniff: false,
+            default_type: "text/plain".to_string(),
+            charset: Some("utf-8".to_string()),
+        }
+    }
+
+    pub fn from_config(config: &crate::config::Config) -> io::Result<Self> {
+        let mut table = MimeTable::new();
+        if let Some(path) = config.mime_types.as_ref() {
+            table.load(path)?;
+        }
+        table.sniff = config.mime_sniff;
+        table.default_type = config.default_type.clone();
+        table.charset = config.charset.clone();
+        Ok(table)
+    }
+
+    /// Loads entries from a file in `/etc/mime.types` format, overriding existing ones.
+    pub fn load(&mut self, path: &str) -> io::Result<()> {
+        let text = fs::read_to_string(path)?;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            if let Some(mime) = words.next() {
+                for ext in words {
+                    self.types.insert(ext.to_lowercase(), mime.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn lookup(&self, filename: &str) -> Option<&str> {
+        let name = filename.rsplit('/').next().unwrap_or(filename);
+        let ext = name.rsplit('.').next().filter(|ext| ext.len() < name.len())?;
+        self.types.get(&ext.to_lowercase()).map(|s| s.as_str())
+    }
+
+    /// Whether `content_type` wants the first bytes of `filename` to decide.
+    pub fn needs_sniff(&self, filename: &str) -> bool {
+        self.sniff && self.lookup(filename).is_none()
+    }
+
+    /// Returns the `Content-Type` value for `filename`. `head` is the beginning of the
+    /// file and is only looked at when the extension is unknown and sniffing is on.
+    pub fn content_type(&self, filename: &str, head: &[u8]) -> String {
+        let mime = self
+            .lookup(filename)
+            .or_else(|| if self.sniff { sniff(head) } else { None })
+            .unwrap_or(self.default_type.as_str());
+
+        match self.charset.as_ref() {
+            Some(charset) if is_text(mime) => format!("{}; charset={}", mime, charset),
+            _ => mime.to_string(),
+        }
+    }
+}
+
+impl Default for MimeTable {
+    fn default() -> Self {
+        MimeTable::new()
+    }
+}
+
+pub fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime == "application/javascript"
+        || mime == "application/json"
+        || mime == "application/xml"
+        || mime == "image/svg+xml"
+}
+
+/// Guesses a MIME type from magic bytes, treating anything without control bytes as text.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(*mime);
+    }
+
+    let text = String::from_utf8_lossy(head);
+    let trimmed = text.trim_start().to_lowercase();
+    if trimmed.starts_with("<!doctype html") || trimmed.starts_with("<html") {
+        Some("text/html")
+    } else if trimmed.starts_with("<?xml") {
+        Some("application/xml")
+    } else if trimmed.starts_with("<svg") {
+        Some("image/svg+xml")
+    } else if !head.is_empty()
+        && !head
+            .iter()
+            .any(|&b| b < 0x09 || (b > 0x0d && b < 0x20 && b != 0x1b))
+    {
+        Some("text/plain")
+    } else if !head.is_empty() {
+        Some("application/octet-stream")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,121 @@
+use crate::{acl::HostPattern, *};
+use std::{
+    io::BufReader,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::prelude::AsyncRead;
+
+/// How long a shadow has to answer before its copy is given up on.
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(30);
+/// Copies sent at once; more are dropped rather than queued.
+const MAX_IN_FLIGHT: usize = 64;
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// A place among the copies in flight, given back when dropped.
+pub struct Slot(());
+
+impl Slot {
+    /// Returns `None` when `MAX_IN_FLIGHT` copies are already out.
+    pub fn take() -> Option<Slot> {
+        if IN_FLIGHT.fetch_add(1, Ordering::SeqCst) >= MAX_IN_FLIGHT {
+            IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            None
+        } else {
+            Some(Slot(()))
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A `mirror <host pattern|*> <path prefix> <shadow host:port> [diff]` directive.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Option<HostPattern>,
+    prefix: String,
+    pub host: String,
+    pub port: u16,
+    /// Log when the shadow's status or body differs from the primary's.
+    pub diff: bool,
+}
+
+impl Rule {
+    pub fn parse(args: &[&str]) -> Result<Rule, String> {
+        if args.len() < 3 || args.len() > 4 {
+            return Err("expected a host pattern, a path prefix, host:port and diff".to_string());
+        }
+
+        let pattern = match args[0] {
+            "*" => None,
+            p => Some(HostPattern::parse(p)?),
+        };
+        let (host, port) = upstream::parse_authority(args[2])?;
+        let diff = match args.get(3) {
+            Some(&"diff") => true,
+            Some(s) => return Err(format!("expected diff, found `{}`", s)),
+            None => false,
+        };
+
+        Ok(Rule {
+            pattern,
+            prefix: args[1].to_string(),
+            host,
+            port,
+            diff,
+        })
+    }
+}
+
+/// Returns the first rule mirroring `req`.
+pub fn select<'a>(rules: &'a [Rule], req: &Request) -> Option<&'a Rule> {
+    rules.iter().find(|r| {
+        r.pattern.as_ref().map_or(true, |p| p.matches(&req.uri.host))
+            && req.uri.path.starts_with(r.prefix.as_str())
+    })
+}
+
+/// A response's status and body hash, which is all a diff compares.
+pub fn summary(resp: &Response) -> (u16, String) {
+    (resp.status, format!("{:x}", md5::compute(&resp.content)))
+}
+
+async fn exchange(req: Request, host: String, port: u16) -> Result<Response, HttpError> {
+    let stream = await!(upstream::connect(host, port))?;
+    let (reader, writer) = stream.split();
+    await!(request(writer, req))?;
+    await!(read_response(BufReader::new(reader)))
+}
+
+/// Sends `req` to the shadow and drops its response, logging failures and, given the
+/// primary's summary, any difference from it. `slot` is held until the copy is done.
+pub async fn send(
+    slot: Slot,
+    req: Request,
+    host: String,
+    port: u16,
+    primary: Option<(u16, String)>,
+) {
+    let target = format!("{} {}", req.method, req.uri.to_string());
+    let shadow = await!(with_timeout(exchange(req, host, port), SHADOW_TIMEOUT));
+    drop(slot);
+
+    match (shadow, primary) {
+        (Err(e), _) => println!("mirror: {} failed: {:?}\n", target, e),
+        (Ok(resp), Some(primary)) => {
+            let shadow = summary(&resp);
+            if shadow != primary {
+                println!(
+                    "mirror: {} differs: primary {} body md5 {}, shadow {} body md5 {}\n",
+                    target, primary.0, primary.1, shadow.0, shadow.1
+                );
+            }
+        }
+        (Ok(_), None) => {}
+    }
+}
@@ -3,41 +3,186 @@
 extern crate futures;
 extern crate proxylab;
 extern crate tokio;
+extern crate tokio_signal;
 
-use futures::{compat::*, future::ready, prelude::*, stream::iter, task::SpawnExt};
-use proxylab::*;
-use std::{env::args, io::BufReader, iter::once, net::ToSocketAddrs};
+use futures::{compat::*, prelude::*, task::SpawnExt};
+use proxylab::{
+    acl,
+    auth::ProxyAuth,
+    balance::Pools,
+    blocklist::Blocklists,
+    config::Config,
+    errors::ErrorPages,
+    fault::{self, Damage},
+    har::{self, Recorder, Timings},
+    limit::{self, Limiter},
+    mirror,
+    replay::{Miss, Replay},
+    retry::Budget,
+    throttle::{self, Link},
+    upstream::Upstream,
+    *,
+};
+use std::{
+    env::args,
+    io::{BufRead, BufReader},
+    iter::once,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
 use tokio::{
     io,
     net::{TcpListener, TcpStream},
-    prelude::AsyncRead,
+    prelude::{AsyncRead, AsyncWrite},
 };
+use tokio_signal::unix::{Signal, SIGHUP};
+
+/// Request bodies up to this size are read whole before forwarding; larger ones are
+/// streamed.
+const BUFFERED_BODY_SIZE: u64 = 64 * 1024;
+
+struct State {
+    config: Config,
+    errors: Arc<ErrorPages>,
+    auth: Option<Arc<ProxyAuth>>,
+    blocklists: RwLock<Blocklists>,
+    pools: Arc<Pools>,
+    retry_budget: Budget,
+    limiter: Arc<Limiter>,
+    recorder: Option<Arc<Recorder>>,
+    replay: Option<Replay>,
+}
 
 fn main() {
     let args = args().collect::<Vec<_>>();
     let port = args.get(1).and_then(|p| p.parse::<usize>().ok());
 
     if port.is_none() {
-        eprintln!("usage: {} <port>\n", args[0]);
+        eprintln!("usage: {} <port> [config]\n", args[0]);
         return;
     }
     let port = port.unwrap();
+
+    let config = match args.get(2).map(|path| Config::load(path)) {
+        Some(Ok(config)) => config,
+        Some(Err(e)) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+        None => Config::default(),
+    };
+    let errors = match ErrorPages::from_config(&config) {
+        Ok(errors) => Arc::new(errors),
+        Err(e) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+    };
+    let auth = match ProxyAuth::from_config(&config) {
+        Ok(auth) => auth.map(Arc::new),
+        Err(e) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+    };
+    let blocklists = match Blocklists::load(&config.blocklists) {
+        Ok(blocklists) => RwLock::new(blocklists),
+        Err(e) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+    };
+    let pools = Arc::new(Pools::new(&config));
+    let retry_budget = Budget::new(config.retry.budget);
+    let limiter = Arc::new(Limiter::new(config.limits.clone()));
+    let recorder = Recorder::new(&config.har).map(Arc::new);
+    let replay = match Replay::load(&config.replay) {
+        Ok(replay) => replay,
+        Err(e) => {
+            eprintln!("config error: {}\n", e);
+            return;
+        }
+    };
+    let state = Arc::new(State {
+        config,
+        errors,
+        auth,
+        blocklists,
+        pools,
+        retry_budget,
+        limiter,
+        recorder,
+        replay,
+    });
+
+    happy_eyeballs::set_attempt_delay(state.config.connect_attempt_delay);
+    dns::configure(state.config.dns.clone());
+
     let addr = format!("0.0.0.0:{}", port).parse().unwrap();
 
     let listener = TcpListener::bind(&addr)
         .unwrap_or_else(|e| panic!("unable to bind TCP listener on {}: {:?}", addr, e));
 
-    let server = async {
+    let socks_listener = state.config.socks_port.map(|port| {
+        let addr = format!("0.0.0.0:{}", port).parse().unwrap();
+        TcpListener::bind(&addr)
+            .unwrap_or_else(|e| panic!("unable to bind SOCKS listener on {}: {:?}", addr, e))
+    });
+    // The admin endpoint shows backend addresses and health, so it stays on loopback
+    // unless configured otherwise.
+    let admin_listener = state.config.admin_addr.map(|addr| {
+        TcpListener::bind(&addr)
+            .unwrap_or_else(|e| panic!("unable to bind admin listener on {}: {:?}", addr, e))
+    });
+
+    let server = async move {
         let mut executor = TokioDefaultSpawner;
+        let _ = executor
+            .spawn(reload_on_sighup(state.clone()))
+            .map_err(|e| eprintln!("spawn failed: {:?}", e));
+        if let Some(socks_listener) = socks_listener {
+            let _ = executor
+                .spawn(serve_socks(socks_listener, state.clone()))
+                .map_err(|e| eprintln!("spawn failed: {:?}", e));
+        }
+        if let Some(admin_listener) = admin_listener {
+            let _ = executor
+                .spawn(serve_admin(admin_listener, state.clone()))
+                .map_err(|e| eprintln!("spawn failed: {:?}", e));
+        }
+        for (i, pool) in state.pools.pools.iter().enumerate() {
+            if pool.active.is_some() {
+                let _ = executor
+                    .spawn(health::run_checks(state.pools.clone(), i))
+                    .map_err(|e| eprintln!("spawn failed: {:?}", e));
+            }
+        }
+
         let mut incomings = listener
             .incoming()
             .compat()
             .map_err(|e| eprintln!("accept failed: {:?}", e));
 
         while let Some(Ok(stream)) = await!(incomings.next()) {
-            let handler = doit(stream).unwrap_or_else(|e| eprintln!("io error: {:?}", e));
+            let permit = match stream.peer_addr() {
+                Ok(client) => Limiter::accept(&state.limiter, client.ip()),
+                Err(e) => {
+                    eprintln!("io error: {:?}", e);
+                    continue;
+                }
+            };
+            let handler = match permit {
+                Ok(permit) => doit(stream, state.clone())
+                    .map(move |result| {
+                        drop(permit);
+                        result
+                    })
+                    .boxed(),
+                Err(limited) => limit::reject(stream, limited, state.errors.clone(), None).boxed(),
+            };
             let _ = executor
-                .spawn(handler)
+                .spawn(handler.unwrap_or_else(|e| eprintln!("io error: {:?}", e)))
                 .map_err(|e| eprintln!("spawn failed: {:?}", e));
         }
     };
@@ -46,63 +191,817 @@ fn main() {
     tokio::run(server);
 }
 
-async fn doit(stream: TcpStream) -> Result<(), io::Error> {
-    let (reader, writer) = stream.split();
-    let reader = BufReader::new(reader);
+async fn doit(stream: TcpStream, state: Arc<State>) -> Result<(), io::Error> {
+    let client = stream.peer_addr()?;
+    // Shaped as configured for every connection until the request names its host.
+    let link = Link::new(throttle::select(&state.config.throttles, None));
+    let reader = BufReader::new(link.client(stream.try_clone()?));
+    let writer = link.client(stream);
 
-    let req = await!(read_request(reader));
-    if let Err(e) = req {
-        return await!(client_error(writer, e));
+    let head = await!(read_request_head(reader));
+    if let Err(e) = head {
+        return await!(error_response(writer, e, state.errors.clone(), None));
     }
-    let req = req.unwrap();
+    let (reader, req) = head.unwrap();
+    let started = SystemTime::now();
+    let accept = req.header("Accept").map(|s| s.to_string());
+    link.set(throttle::select(&state.config.throttles, Some(&req.uri.host)));
 
+    let user = match state.auth.clone() {
+        Some(auth) => {
+            // bcrypt is slow enough to stall every other connection on this reactor thread.
+            let authenticated = if auth.is_slow(&req) {
+                let (auth, req) = (auth.clone(), req.clone());
+                await!(blocking(move || auth.authenticate(&req))).unwrap_or(Err(false))
+            } else {
+                auth.authenticate(&req)
+            };
+            match authenticated {
+                Ok(user) => Some(user),
+                Err(stale) => {
+                    let info = HttpError::ProxyAuthRequired(req.uri.to_string()).info();
+                    let mut resp = state.errors.render(
+                        info.0,
+                        &info.1,
+                        &info.2,
+                        &info.3,
+                        accept.as_ref().map(|s| s.as_str()),
+                    );
+                    for challenge in auth.challenges(stale) {
+                        resp.headers.push(format!("Proxy-Authenticate: {}", challenge));
+                    }
+                    println!("proxy authentication failed: {}\n", req.uri.to_string());
+                    return await!(response(writer, resp));
+                }
+            }
+        }
+        None => None,
+    };
+
+    // The credential is meant for this proxy only and must not reach the upstream.
+    let headers = without_header(&req.headers, "Proxy-Authorization");
+    let req = Request { headers, ..req };
+
+    if let Some(user) = user.as_ref() {
+        println!("user: {}", user);
+    }
     await!(req.log());
 
+    let limited = state.limiter.request(client.ip()).and_then(|_| match user.as_ref() {
+        Some(user) => state.limiter.user_request(user),
+        None => Ok(()),
+    });
+    if let Err(limited) = limited {
+        return await!(limit::reject(writer, limited, state.errors.clone(), accept));
+    }
+
+    // With routes configured the proxy is the origin, and only routed requests are served.
+    let route = if state.config.routes.is_empty() {
+        None
+    } else {
+        match reverse::select(&state.config.routes, &req.uri.host, &req.uri.path) {
+            Some(route) => Some(route.clone()),
+            None => {
+                let e = HttpError::NotFound(format!("no route for {}", req.uri.to_string()));
+                return await!(error_response(writer, e, state.errors.clone(), accept));
+            }
+        }
+    };
+    // A replayed request stays off the network, so its destination is only checked if it
+    // misses the recording and goes out after all.
+    let replaying = state.replay.is_some() && req.method != "CONNECT";
+    // Backends come from the config, so routed requests skip only the rules on the address
+    // they go to.
+    let pinned = if route.is_some() {
+        if let Err(e) = check_request(client.ip(), &req, &[], &state) {
+            return await!(error_response(writer, e, state.errors.clone(), accept));
+        }
+        None
+    } else if !replaying {
+        match await!(check_destination(client.ip(), req.clone(), state.clone())) {
+            Ok(pinned) => pinned,
+            Err(e) => return await!(error_response(writer, e, state.errors.clone(), accept)),
+        }
+    } else {
+        None
+    };
+
+    if req.method == "CONNECT" {
+        return await!(tunnel(reader, writer, req, pinned, client.ip(), link, state, accept));
+    }
+
+    let limit = state.config.max_body_size;
+    let framing = match body_framing(&req.headers) {
+        Ok(Framing::Length(len)) if len > limit => Err(too_large(limit)),
+        framing => framing,
+    };
+    if let Err(e) = framing {
+        return await!(error_response(writer, e, state.errors.clone(), accept));
+    }
+    let framing = framing.unwrap();
+    // A body too big to hold goes to the upstream as it arrives, so it is sent only once,
+    // has no mirror copy and is left out of HAR entries. Replay needs every body to match.
+    let streamed = state.replay.is_none()
+        && match framing {
+            Framing::Length(len) => len > BUFFERED_BODY_SIZE,
+            Framing::Chunked => true,
+        };
+    let (reader, req) = if streamed {
+        (reader, req)
+    } else {
+        match await!(read_request_body(reader, req, limit)) {
+            Ok(read) => read,
+            Err(e) => return await!(error_response(writer, e, state.errors.clone(), accept)),
+        }
+    };
+    let body = if streamed {
+        Some(Streamed { reader, framing })
+    } else {
+        None
+    };
+
+    let faults = fault::roll(&state.config.faults, &req);
+    if faults.delay > Duration::from_millis(0) {
+        println!("fault: delay {:?} {}\n", faults.delay, req.uri.to_string());
+        await!(sleep(faults.delay));
+    }
+    if let Some(status) = faults.status {
+        println!("fault: status {} {}\n", status, req.uri.to_string());
+        let resp = state.errors.render(
+            status,
+            "InjectedFault",
+            "The proxy injected this response for fault testing",
+            &req.uri.to_string(),
+            accept.as_ref().map(|s| s.as_str()),
+        );
+        return await!(response(writer, resp));
+    }
+
+    let replayed = state.replay.as_ref().map(|r| (r.find(&req), r.miss.clone()));
+    let replayed = match replayed {
+        Some((Some(resp), _)) => {
+            println!("replay: {} {}\n", req.method, req.uri.to_string());
+            Some(resp)
+        }
+        Some((None, Miss::Pass)) | None => None,
+        Some((None, miss)) => {
+            let detail = format!("nothing recorded for {}", req.uri.to_string());
+            let e = match miss {
+                Miss::Error => HttpError::BadGateway(detail),
+                _ => HttpError::NotFound(detail),
+            };
+            return await!(error_response(writer, e, state.errors.clone(), accept));
+        }
+    };
+    let pinned = if replaying && replayed.is_none() && route.is_none() {
+        match await!(check_destination(client.ip(), req.clone(), state.clone())) {
+            Ok(pinned) => pinned,
+            Err(e) => return await!(error_response(writer, e, state.errors.clone(), accept)),
+        }
+    } else {
+        pinned
+    };
+
+    // The shadow gets the request as the primary upstream would, rewritten by the route.
+    let mirrored = if streamed {
+        None
+    } else {
+        mirror::select(&state.config.mirrors, &req)
+    };
+    let mirroring = mirrored.map(|rule| {
+        let shadow = match route.as_ref() {
+            Some(route) => route.apply(req.clone(), client.ip(), (rule.host.clone(), rule.port)),
+            None => Request {
+                uri: Uri {
+                    host: rule.host.clone(),
+                    port: rule.port,
+                    path: req.uri.path.clone(),
+                },
+                ..req.clone()
+            },
+        };
+        (shadow, rule.clone())
+    });
+
+    // The cache is keyed by what the client asked for, in front of any rewriting.
     let uri = req.uri.clone();
     let cached_resp = await!(cache::find_cache_block(uri.clone()));
+    let recording = state.recorder.clone().map(|recorder| (recorder, req.clone()));
 
-    let resp = if let Some(resp) = cached_resp {
-        resp
+    let uploaded = req.body.len() as u64;
+    let (resp, timings, uploaded) = if let Some(resp) = replayed.or(cached_resp) {
+        (resp, Timings::default(), uploaded)
     } else {
-        let resp = await!(request_server(req));
+        let resp = match route {
+            Some(route) => await!(request_backend(
+                req,
+                body,
+                route,
+                client.ip(),
+                link,
+                state.clone()
+            )),
+            None => await!(request_server(req, body, pinned, link, state.clone())),
+        };
         if let Err(e) = resp {
-            return await!(client_error(writer, e));
+            spawn_mirror(mirroring, None);
+            return await!(error_response(writer, e, state.errors.clone(), accept));
         }
         resp.unwrap()
     };
+    spawn_mirror(mirroring, Some(&resp));
+
+    if let Some((recorder, req)) = recording {
+        // The client gets its response without waiting for the write.
+        let entry = har::entry(started, &req, &resp, &timings, recorder.body_limit());
+        recorder.record(entry);
+    }
 
-    await!(cache::add_cache_block(uri, resp.clone()));
+    state
+        .limiter
+        .transferred(client.ip(), uploaded + resp.content.len() as u64);
+
+    // Upstream error bodies pass through unchanged unless configured otherwise.
+    if state.config.proxy_intercept_errors && resp.status >= 400 {
+        let detail = uri.to_string();
+        let resp = state.errors.render(
+            resp.status,
+            &resp.reason,
+            "The upstream server returned an error",
+            &detail,
+            accept.as_ref().map(|s| s.as_str()),
+        );
+        return await!(response(writer, resp));
+    }
+
+    await!(cache::add_cache_block(uri.clone(), resp.clone()));
+
+    if let Some(damage) = faults.damage {
+        println!("fault: {:?} {}\n", damage, uri.to_string());
+        let writer = await!(fault::send_damaged(writer, resp, damage))?;
+        if damage == Damage::Reset {
+            // Lingering for no time makes closing the socket send a reset.
+            writer.get_ref().set_linger(Some(Duration::from_secs(0)))?;
+        }
+        return Ok(());
+    }
 
     await!(response(writer, resp))
 }
 
-async fn request_server(req: Request) -> Result<Response, HttpError> {
-    let addrs = (req.uri.host.as_ref(), req.uri.port)
-        .to_socket_addrs()
-        .map_err(|e| HttpError::Error(format!("parsing socket addr failed: {:?}", e)))?;
+/// Accepts SOCKS5 clients alongside the HTTP listener.
+async fn serve_socks(listener: TcpListener, state: Arc<State>) {
+    let mut executor = TokioDefaultSpawner;
+    let mut incomings = listener
+        .incoming()
+        .compat()
+        .map_err(|e| eprintln!("accept failed: {:?}", e));
 
-    let stream = await!(iter(addrs)
-        .then(|addr| TcpStream::connect(&addr).compat())
-        .map(|r| r.map_err(|e| HttpError::Error(format!("connecting failed: {:?}", e))))
-        .fold(
-            Err(HttpError::Error("empty socket addrs".to_string())),
-            |acc, s| ready(if acc.is_ok() { acc } else { s }),
-        ))?;
-    let (reader, writer) = stream.split();
+    while let Some(Ok(stream)) = await!(incomings.next()) {
+        // There is no way to say why in SOCKS, so connections over the caps are just closed.
+        let permit = match stream.peer_addr() {
+            Ok(client) => match Limiter::accept(&state.limiter, client.ip()) {
+                Ok(permit) => permit,
+                Err(_) => {
+                    println!("socks: too many connections from {}\n", client);
+                    continue;
+                }
+            },
+            Err(e) => {
+                eprintln!("io error: {:?}", e);
+                continue;
+            }
+        };
+        let handler = doit_socks(stream, state.clone())
+            .map(move |result| {
+                drop(permit);
+                result
+            })
+            .unwrap_or_else(|e| eprintln!("io error: {:?}", e));
+        let _ = executor
+            .spawn(handler)
+            .map_err(|e| eprintln!("spawn failed: {:?}", e));
+    }
+}
+
+/// Accepts connections to the admin endpoint, which reports backend health.
+async fn serve_admin(listener: TcpListener, state: Arc<State>) {
+    let mut executor = TokioDefaultSpawner;
+    let mut incomings = listener
+        .incoming()
+        .compat()
+        .map_err(|e| eprintln!("accept failed: {:?}", e));
+
+    while let Some(Ok(stream)) = await!(incomings.next()) {
+        let handler = doit_admin(stream, state.clone())
+            .unwrap_or_else(|e| eprintln!("io error: {:?}", e));
+        let _ = executor
+            .spawn(handler)
+            .map_err(|e| eprintln!("spawn failed: {:?}", e));
+    }
+}
+
+/// Answers `GET /health` with the state of every pool member as JSON.
+async fn doit_admin(stream: TcpStream, state: Arc<State>) -> Result<(), io::Error> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let writer = stream;
+
+    let req = await!(read_request(reader, state.config.max_body_size));
+    if let Err(e) = req {
+        return await!(error_response(writer, e, state.errors.clone(), None));
+    }
+    let req = req.unwrap();
+    if req.method != "GET" || req.uri.path != "/health" {
+        let e = HttpError::NotFound(req.uri.path.clone());
+        return await!(error_response(writer, e, state.errors.clone(), None));
+    }
+
+    let content = health::report(&state.pools).into_bytes();
+    let resp = Response {
+        version: "HTTP/1.0".to_string(),
+        status: 200,
+        reason: "OK".to_string(),
+        headers: vec![
+            "Content-Type: application/json".to_string(),
+            format!("Content-Length: {}", content.len()),
+        ],
+        content,
+    };
+
+    await!(response(writer, resp))
+}
+
+/// Reloads the blocklists from disk whenever the process receives SIGHUP.
+async fn reload_on_sighup(state: Arc<State>) {
+    let signals = await!(Signal::new(SIGHUP).compat());
+    if let Err(e) = signals {
+        eprintln!("unable to listen for SIGHUP: {:?}", e);
+        return;
+    }
+    let mut signals = signals.unwrap().compat();
+
+    while let Some(Ok(_)) = await!(signals.next()) {
+        // Reading the files and compiling a regex per adblock rule would stall the reactor.
+        let sources = state.config.blocklists.clone();
+        let loaded = await!(blocking(move || Blocklists::load(&sources)));
+        match loaded.map_err(|e| e.info().3).and_then(|loaded| loaded) {
+            Ok(fresh) => {
+                let mut blocklists = state.blocklists.write().unwrap();
+                fresh.keep_hits(&blocklists);
+                *blocklists = fresh;
+                println!("blocklists reloaded");
+                blocklists.log();
+            }
+            Err(e) => eprintln!("blocklist reload failed, keeping old lists: {}\n", e),
+        }
+    }
+}
+
+/// Sends the copy of a mirrored request off on its own, so the client never waits for it.
+fn spawn_mirror(mirroring: Option<(Request, mirror::Rule)>, primary: Option<&Response>) {
+    if let Some((shadow, rule)) = mirroring {
+        let slot = match mirror::Slot::take() {
+            Some(slot) => slot,
+            None => {
+                let target = format!("{} {}", shadow.method, shadow.uri.to_string());
+                println!("mirror: {} dropped, too many copies in flight\n", target);
+                return;
+            }
+        };
+        let primary = primary.filter(|_| rule.diff).map(mirror::summary);
+        let mut executor = TokioDefaultSpawner;
+        let _ = executor
+            .spawn(mirror::send(slot, shadow, rule.host, rule.port, primary))
+            .map_err(|e| eprintln!("spawn failed: {:?}", e));
+    }
+}
+
+/// Answers `CONNECT` by relaying bytes both ways between the client and the destination.
+async fn tunnel(
+    reader: impl AsyncRead + BufRead,
+    writer: impl AsyncWrite,
+    req: Request,
+    pinned: Option<Vec<IpAddr>>,
+    client: IpAddr,
+    link: Link,
+    state: Arc<State>,
+    accept: Option<String>,
+) -> Result<(), io::Error> {
+    let upstream = select_upstream(&req, pinned, &state);
+    let server = await!(upstream::open_tunnel(
+        upstream,
+        req.uri.host.clone(),
+        req.uri.port
+    ));
+    if let Err(e) = server {
+        return await!(error_response(writer, e, state.errors.clone(), accept));
+    }
+    let server = server.unwrap();
+
+    let established = "HTTP/1.1 200 Connection Established\r\n\r\n";
+    let (writer, _) = await!(io::write_all(writer, established).compat())?;
+
+    let server_reader = link.upstream(server.try_clone()?);
+    let transferred = await!(relay(reader, writer, server_reader, server))?;
+    state.limiter.transferred(client, transferred);
+    Ok(())
+}
+
+/// Serves one SOCKS5 client: authentication, then a `CONNECT` relayed like an HTTP tunnel.
+async fn doit_socks(stream: TcpStream, state: Arc<State>) -> Result<(), io::Error> {
+    let client = stream.peer_addr()?;
+
+    let (stream, acceptable) = await!(socks::negotiate(stream, state.auth.is_some()))?;
+    if !acceptable {
+        println!("socks: no acceptable authentication method from {}\n", client);
+        return Ok(());
+    }
+
+    let (stream, user) = match state.auth.as_ref() {
+        Some(auth) => {
+            let (stream, user, password) = await!(socks::read_credentials(stream))?;
+            let ok = if auth.is_slow_password(&user, &password) {
+                let (auth, user) = (auth.clone(), user.clone());
+                await!(blocking(move || auth.verify_password(&user, &password))).unwrap_or(false)
+            } else {
+                auth.verify_password(&user, &password)
+            };
+            let stream = await!(socks::credentials_status(stream, ok))?;
+            if !ok {
+                println!("proxy authentication failed: socks user {}\n", user);
+                return Ok(());
+            }
+            (stream, Some(user))
+        }
+        None => (stream, None),
+    };
+
+    let (stream, command) = await!(socks::read_command(stream))?;
+    let (host, port) = match command {
+        socks::Command::Connect { host, port } => (host, port),
+        socks::Command::Unsupported(command) => {
+            println!("socks: unsupported command {:#04x}\n", command);
+            return Ok(());
+        }
+    };
+    let req = Request {
+        method: "CONNECT".to_string(),
+        uri: Uri {
+            host,
+            port,
+            path: String::new(),
+        },
+        version: "SOCKS5".to_string(),
+        headers: vec![],
+        body: vec![],
+    };
+
+    if let Some(user) = user.as_ref() {
+        println!("user: {}", user);
+    }
+    await!(req.log());
+
+    let limited = state.limiter.request(client.ip()).and_then(|_| match user.as_ref() {
+        Some(user) => state.limiter.user_request(user),
+        None => Ok(()),
+    });
+    if let Err(limited) = limited {
+        println!("socks: {:?}\n", limited);
+        await!(socks::reply(stream, socks::Reply::NotAllowed, None))?;
+        return Ok(());
+    }
+
+    let pinned = match await!(check_destination(client.ip(), req.clone(), state.clone())) {
+        Ok(pinned) => pinned,
+        Err(e) => {
+            println!("socks: {:?}\n", e);
+            await!(socks::reply(stream, socks::Reply::NotAllowed, None))?;
+            return Ok(());
+        }
+    };
+
+    let upstream = select_upstream(&req, pinned, &state);
+    let server = await!(upstream::open_tunnel(
+        upstream,
+        req.uri.host.clone(),
+        req.uri.port
+    ));
+    if let Err(e) = server {
+        println!("socks: {:?}\n", e);
+        await!(socks::reply(stream, socks::Reply::HostUnreachable, None))?;
+        return Ok(());
+    }
+    let server = server.unwrap();
+
+    let bound = server.local_addr().ok();
+    let stream = await!(socks::reply(stream, socks::Reply::Succeeded, bound))?;
+
+    let link = Link::new(throttle::select(&state.config.throttles, Some(&req.uri.host)));
+    let (reader, writer) = (link.client(stream.try_clone()?), link.client(stream));
+    let server_reader = link.upstream(server.try_clone()?);
+    let transferred = await!(relay(reader, writer, server_reader, server))?;
+    state.limiter.transferred(client.ip(), transferred);
+    Ok(())
+}
+
+/// Copies bytes both ways between a client and a server until both sides are done, and
+/// returns how many were copied in all.
+async fn relay(
+    reader: impl AsyncRead,
+    writer: impl AsyncWrite,
+    server_reader: impl AsyncRead,
+    server: TcpStream,
+) -> Result<u64, io::Error> {
+    // Each direction half-closes its destination once its source hits EOF.
+    let upload = async move {
+        let (n, _, server) = await!(io::copy(reader, server).compat())?;
+        await!(io::shutdown(server).compat())?;
+        Ok::<_, io::Error>(n)
+    };
+    let download = async move {
+        let (n, _, writer) = await!(io::copy(server_reader, writer).compat())?;
+        await!(io::shutdown(writer).compat())?;
+        Ok::<_, io::Error>(n)
+    };
+    let (upload, download) = await!(upload.join(download));
+    Ok(upload? + download?)
+}
+
+/// Resolves the destination of `req` and runs it through the ACL and the blocklists.
+/// Returns the addresses that were checked, which are the only ones to connect to.
+///
+/// A destination reached through a parent proxy is resolved there, possibly to names only
+/// it knows, so it is checked without addresses and nothing is pinned.
+async fn check_destination(
+    client: IpAddr,
+    req: Request,
+    state: Arc<State>,
+) -> Result<Option<Vec<IpAddr>>, HttpError> {
+    let upstream = upstream::select(&state.config.parent_proxies, &req.uri.host);
+    if let Upstream::Http { .. } | Upstream::Socks5 { .. } = upstream {
+        check_request(client, &req, &[], &state)?;
+        return Ok(None);
+    }
+
+    // A destination that cannot be resolved cannot be checked against `dst=` rules.
+    let addrs = await!(dns::resolve(req.uri.host.clone())).map_err(|e| {
+        let uri = req.uri.to_string();
+        HttpError::Forbidden(format!("{} {} denied: {}", req.method, uri, e.info().3))
+    })?;
+
+    check_request(client, &req, &addrs, &state)?;
+    Ok(Some(addrs))
+}
+
+/// Runs `req` through the ACL, as going to `addrs`, and the blocklists.
+fn check_request(
+    client: IpAddr,
+    req: &Request,
+    addrs: &[IpAddr],
+    state: &State,
+) -> Result<(), HttpError> {
+    if let Err(rule) = check_acl(client, req, addrs, state) {
+        return Err(HttpError::Forbidden(format!(
+            "{} {} denied by acl rule `{}`",
+            req.method,
+            req.uri.to_string(),
+            rule
+        )));
+    }
+
+    // Lists name hosts without the trailing dot a client may add.
+    let uri = Uri {
+        host: acl::normalize_host(&req.uri.host),
+        ..req.uri.clone()
+    };
+    let blocklists = state.blocklists.read().unwrap();
+    match blocklists.check(&uri.host, &uri.to_string()) {
+        Some(name) => Err(HttpError::Forbidden(format!(
+            "{} blocked by list `{}`",
+            req.uri.to_string(),
+            name
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The upstream for `req`, kept to the addresses `check_destination` checked if any.
+fn select_upstream(req: &Request, pinned: Option<Vec<IpAddr>>, state: &State) -> Upstream {
+    let upstream = upstream::select(&state.config.parent_proxies, &req.uri.host);
+    match pinned {
+        Some(addrs) => upstream.pin(addrs),
+        None => upstream,
+    }
+}
+
+/// Runs the destination through the ACL, returning the denying rule.
+fn check_acl(
+    client: IpAddr,
+    req: &Request,
+    addrs: &[IpAddr],
+    state: &State,
+) -> Result<(), String> {
+    state.config.acl.check(&acl::Target {
+        client,
+        host: &req.uri.host,
+        addrs,
+        port: req.uri.port,
+        method: &req.method,
+    })
+}
+
+/// A request body left on the client connection, to be copied to the upstream as it
+/// arrives.
+struct Streamed<R> {
+    reader: R,
+    framing: Framing,
+}
+
+/// Why an attempt at the upstream failed.
+#[derive(Debug)]
+enum Failure {
+    /// Nothing came back, so an idempotent request may be sent again.
+    BeforeResponse(HttpError),
+    AfterResponse(HttpError),
+}
+
+impl Failure {
+    fn into_error(self) -> HttpError {
+        match self {
+            Failure::BeforeResponse(e) | Failure::AfterResponse(e) => e,
+        }
+    }
+}
+
+/// Decides whether `method` gets its `retry`th retry, and waits out the backoff if so.
+async fn backoff_before_retry(method: String, retry: u32, state: Arc<State>) -> bool {
+    let policy = &state.config.retry;
+    if retry > policy.retries
+        || !retry::Policy::is_idempotent(&method)
+        || !state.retry_budget.try_retry()
+    {
+        return false;
+    }
+
+    let delay = policy.backoff(retry);
+    println!(
+        "retrying {} in {:?} (retry {} of {})\n",
+        method, delay, retry, policy.retries
+    );
+    await!(sleep(delay));
+    true
+}
+
+/// Sends a routed request to the route's backend, or to one picked from its pool. Retries
+/// go to a pool member not tried yet when there is one.
+async fn request_backend<R: AsyncRead + BufRead + Send + 'static>(
+    req: Request,
+    body: Option<Streamed<R>>,
+    route: reverse::Route,
+    client: IpAddr,
+    link: Link,
+    state: Arc<State>,
+) -> Result<(Response, Timings, u64), HttpError> {
+    state.retry_budget.request();
+    let streamed = body.is_some();
+    let mut body = body;
+    let mut tried = vec![];
+    let mut retry = 0;
+
+    loop {
+        let (backend, lease) = match &route.destination {
+            reverse::Destination::Addr(host, port) => ((host.clone(), *port), None),
+            reverse::Destination::Pool(name) => {
+                let pool = state.pools.get(name);
+                match pool.and_then(|p| p.pick(client, &req, &tried)) {
+                    Some(lease) => {
+                        let backend = (lease.backend.host.clone(), lease.backend.port);
+                        (backend, Some(lease))
+                    }
+                    None => {
+                        return Err(HttpError::ServiceUnavailable(format!(
+                            "no backend available in pool `{}`",
+                            name
+                        )))
+                    }
+                }
+            }
+        };
+
+        let routed = route.apply(req.clone(), client, backend);
+        let resp = await!(attempt(routed, body.take(), None, link.clone(), state.clone()));
+
+        if let (Some(lease), reverse::Destination::Pool(name)) = (lease, &route.destination) {
+            let result = match resp.as_ref() {
+                Ok((resp, ..)) if resp.status >= 500 => Err(format!("status {}", resp.status)),
+                Ok(_) => Ok(()),
+                Err(failure) => Err(format!("{:?}", failure)),
+            };
+            if let Some(pool) = state.pools.get(name) {
+                pool.report(&lease.backend, result);
+            }
+            tried.push(lease.backend.authority());
+        }
+
+        match resp {
+            Ok(resp) => return Ok(resp),
+            Err(Failure::BeforeResponse(e)) => {
+                retry += 1;
+                if streamed
+                    || !await!(backoff_before_retry(req.method.clone(), retry, state.clone()))
+                {
+                    return Err(e);
+                }
+            }
+            Err(failure) => return Err(failure.into_error()),
+        }
+    }
+}
+
+async fn request_server<R: AsyncRead + BufRead + Send + 'static>(
+    req: Request,
+    body: Option<Streamed<R>>,
+    pinned: Option<Vec<IpAddr>>,
+    link: Link,
+    state: Arc<State>,
+) -> Result<(Response, Timings, u64), HttpError> {
+    state.retry_budget.request();
+    let streamed = body.is_some();
+    let mut body = body;
+    let mut retry = 0;
+
+    loop {
+        let pinned = pinned.clone();
+        match await!(attempt(req.clone(), body.take(), pinned, link.clone(), state.clone())) {
+            Ok(resp) => return Ok(resp),
+            Err(Failure::BeforeResponse(e)) => {
+                retry += 1;
+                if streamed
+                    || !await!(backoff_before_retry(req.method.clone(), retry, state.clone()))
+                {
+                    return Err(e);
+                }
+            }
+            Err(failure) => return Err(failure.into_error()),
+        }
+    }
+}
+
+/// Sends `req` once, followed by its streamed body if any, noting whether any of the
+/// response had arrived when it failed. Also returns how many body bytes were sent.
+async fn attempt<R: AsyncRead + BufRead + Send + 'static>(
+    req: Request,
+    body: Option<Streamed<R>>,
+    pinned: Option<Vec<IpAddr>>,
+    link: Link,
+    state: Arc<State>,
+) -> Result<(Response, Timings, u64), Failure> {
+    let upstream = select_upstream(&req, pinned, &state);
+    let mut timings = Timings::default();
+
+    // Resolving first times the lookup apart from the connect, which then hits the cache.
+    let start = Instant::now();
+    let host = upstream.connect_host(&req.uri.host).to_string();
+    await!(dns::resolve(host)).map_err(Failure::BeforeResponse)?;
+    timings.dns = Some(start.elapsed());
+
+    let start = Instant::now();
+    let (stream, req) =
+        await!(upstream::open(upstream, req)).map_err(Failure::BeforeResponse)?;
+    timings.connect = Some(start.elapsed());
+    let (reader, writer) = link.upstream(stream).split();
     let reader = BufReader::new(reader);
 
-    await!(request(writer, req))?;
+    let start = Instant::now();
+    let mut uploaded = req.body.len() as u64;
+    let writer = await!(request(writer, req)).map_err(Failure::BeforeResponse)?;
+    if let Some(body) = body {
+        let limit = state.config.max_body_size;
+        let copied = await!(copy_body(body.reader, writer, body.framing, limit, true));
+        let (_, _, n) = copied.map_err(Failure::BeforeResponse)?;
+        uploaded = n;
+    }
+    timings.send = start.elapsed();
+
+    let start = Instant::now();
+    let reader = await!(first_byte(reader)).map_err(|e| {
+        let e = HttpError::BadGateway(format!("upstream closed before responding: {:?}", e));
+        Failure::BeforeResponse(e)
+    })?;
+    timings.wait = start.elapsed();
 
-    let resp = await!(read_response(reader))?;
+    let start = Instant::now();
+    let resp = await!(read_response(reader)).map_err(Failure::AfterResponse)?;
+    timings.receive = start.elapsed();
 
-    let headers: Vec<_> = resp
-        .headers
-        .iter()
-        .filter(|h| {
-            !h.starts_with("Content-Length:")
-                && !(h.starts_with("Transfer-Encoding:") && h.contains("chunked"))
-        })
-        .map(|s| s.to_string())
+    // The body was read whole and de-chunked, so its framing headers must say so.
+    let headers = if is_chunked(&resp.headers) {
+        without_header(&resp.headers, "Transfer-Encoding")
+    } else {
+        resp.headers.clone()
+    };
+    let headers = without_header(&headers, "Content-Length")
+        .into_iter()
         .chain(once(format!("Content-Length: {}", resp.content.len())))
         .collect();
 
@@ -110,5 +1009,5 @@ async fn request_server(req: Request) -> Result<Response, HttpError> {
 
     await!(resp.log());
 
-    Ok(resp)
+    Ok((resp, timings, uploaded))
 }
@@ -0,0 +1,240 @@
+use crate::{
+    json::{self, Value},
+    *,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    iter::once,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// What to do with a request nothing was recorded for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Miss {
+    /// Answer `404 Not Found`, as an origin without the resource would.
+    NotFound,
+    /// Forward it over the network as usual.
+    Pass,
+    /// Answer `502 Bad Gateway`, so a test relying on the recording fails loudly.
+    Error,
+}
+
+/// The `replay <file|directory>`, `replay_match_header <name>...`, `replay_match_body
+/// <on|off>` and `replay_miss <404|pass|error>` settings.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub sources: Vec<String>,
+    /// Request headers that must match as well as the method and URI.
+    pub headers: Vec<String>,
+    pub body: bool,
+    pub miss: Miss,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            sources: vec![],
+            headers: vec![],
+            body: false,
+            miss: Miss::NotFound,
+        }
+    }
+}
+
+impl Miss {
+    pub fn parse(s: &str) -> Result<Miss, String> {
+        match s {
+            "404" => Ok(Miss::NotFound),
+            "pass" => Ok(Miss::Pass),
+            "error" => Ok(Miss::Error),
+            _ => Err(format!("expected 404, pass or error, found `{}`", s)),
+        }
+    }
+}
+
+/// The responses recorded for one request, served in order and then the last one again.
+#[derive(Debug)]
+struct Recorded {
+    responses: Vec<Response>,
+    next: AtomicUsize,
+}
+
+/// Recorded exchanges keyed by what a request has to match.
+#[derive(Debug)]
+pub struct Replay {
+    headers: Vec<String>,
+    body: bool,
+    pub miss: Miss,
+    recorded: HashMap<String, Recorded>,
+}
+
+impl Replay {
+    /// Loads every entry of the configured HAR files, and of the `.har` files in the
+    /// configured directories in name order; `None` when replay is off.
+    pub fn load(settings: &Settings) -> Result<Option<Replay>, String> {
+        if settings.sources.is_empty() {
+            return Ok(None);
+        }
+
+        let mut replay = Replay {
+            headers: settings.headers.clone(),
+            body: settings.body,
+            miss: settings.miss.clone(),
+            recorded: HashMap::new(),
+        };
+        for source in settings.sources.iter() {
+            if Path::new(source).is_dir() {
+                let mut files = fs::read_dir(source)
+                    .map_err(|e| format!("reading {} failed: {:?}", source, e))?
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|path| path.extension().map_or(false, |ext| ext == "har"))
+                    .collect::<Vec<_>>();
+                files.sort();
+                for file in files {
+                    replay.load_file(&file.display().to_string())?;
+                }
+            } else {
+                replay.load_file(source)?;
+            }
+        }
+
+        let entries = replay
+            .recorded
+            .values()
+            .map(|r| r.responses.len())
+            .sum::<usize>();
+        println!(
+            "replay: {} responses for {} requests\n",
+            entries,
+            replay.recorded.len()
+        );
+        Ok(Some(replay))
+    }
+
+    fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("reading {} failed: {:?}", path, e))?;
+        let har = json::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+        let entries = har
+            .get("log")
+            .and_then(|log| log.get("entries"))
+            .and_then(|entries| entries.as_array())
+            .ok_or_else(|| format!("{}: no log.entries array", path))?;
+
+        for (i, entry) in entries.iter().enumerate() {
+            let (key, resp) = self
+                .parse_entry(entry)
+                .ok_or_else(|| format!("{}: entry {} is incomplete", path, i))?;
+            self.recorded
+                .entry(key)
+                .or_insert_with(|| Recorded {
+                    responses: vec![],
+                    next: AtomicUsize::new(0),
+                })
+                .responses
+                .push(resp);
+        }
+        Ok(())
+    }
+
+    fn parse_entry(&self, entry: &Value) -> Option<(String, Response)> {
+        let request = entry.get("request")?;
+        let headers = pairs(request.get("headers")?)?;
+        let request_body = request.get("postData").map_or(Some(vec![]), body)?;
+        let key = self.key(
+            request.get("method")?.as_str()?,
+            request.get("url")?.as_str()?,
+            |name| {
+                headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.clone())
+            },
+            &request_body,
+        );
+
+        let response = entry.get("response")?;
+        let content = body(response.get("content")?)?;
+        // The recorded body is whole and unchunked, whatever the recorded headers say.
+        let headers = pairs(response.get("headers")?)?
+            .into_iter()
+            .filter(|(name, _)| {
+                !name.eq_ignore_ascii_case("Content-Length")
+                    && !name.eq_ignore_ascii_case("Transfer-Encoding")
+            })
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .chain(once(format!("Content-Length: {}", content.len())))
+            .collect();
+        // The recorded `httpVersion` may be `h2` or the like; `find` answers in the
+        // client's own version instead.
+        let resp = Response {
+            version: String::new(),
+            status: response.get("status")?.as_f64()? as u16,
+            reason: response.get("statusText")?.as_str()?.to_string(),
+            headers,
+            content,
+        };
+
+        Some((key, resp))
+    }
+
+    fn key(
+        &self,
+        method: &str,
+        url: &str,
+        header: impl Fn(&str) -> Option<String>,
+        body: &[u8],
+    ) -> String {
+        let mut key = format!("{} {}\n", method, url);
+        for name in self.headers.iter() {
+            key += &format!("{}: {}\n", name.to_lowercase(), header(name).unwrap_or_default());
+        }
+        if self.body {
+            key += &format!("{:x}\n", md5::compute(body));
+        }
+        key
+    }
+
+    /// The next recorded response for `req`, in its HTTP version, if anything was recorded
+    /// for it.
+    pub fn find(&self, req: &Request) -> Option<Response> {
+        let key = self.key(
+            &req.method,
+            &req.uri.to_string(),
+            |name| req.header(name).map(|v| v.to_string()),
+            &req.body,
+        );
+        let recorded = self.recorded.get(&key)?;
+        let i = recorded.next.fetch_add(1, Ordering::Relaxed);
+        let resp = recorded
+            .responses
+            .get(i)
+            .or_else(|| recorded.responses.last())?;
+        Some(Response {
+            version: req.version.clone(),
+            ..resp.clone()
+        })
+    }
+}
+
+/// Reads a HAR `[{"name": ..., "value": ...}]` list.
+fn pairs(list: &Value) -> Option<Vec<(String, String)>> {
+    list.as_array()?
+        .iter()
+        .map(|pair| {
+            let name = pair.get("name")?.as_str()?.to_string();
+            Some((name, pair.get("value")?.as_str()?.to_string()))
+        })
+        .collect()
+}
+
+/// Reads the `text` of a HAR `content` or `postData`, decoding base64 when marked so.
+fn body(content: &Value) -> Option<Vec<u8>> {
+    let text = content.get("text").and_then(|t| t.as_str()).unwrap_or_default();
+    match content.get("encoding").and_then(|e| e.as_str()) {
+        Some("base64") => base64::decode(text).ok(),
+        _ => Some(text.as_bytes().to_vec()),
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// Methods that may be sent again after an attempt that got no response.
+const IDEMPOTENT: &[&str] = &["GET", "HEAD", "OPTIONS", "PUT", "DELETE"];
+
+/// How long the retry budget counts requests before starting over.
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Retries allowed per budget window whatever the traffic, so a quiet proxy still retries.
+const BUDGET_MIN_RETRIES: u32 = 3;
+
+/// The `retries <n>`, `retry_backoff <base ms> <max ms>` and `retry_budget <percent>`
+/// settings.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub retries: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    /// Retries as a percentage of recent requests.
+    pub budget: u32,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            retries: 2,
+            backoff_base: Duration::from_millis(50),
+            backoff_max: Duration::from_secs(1),
+            budget: 20,
+        }
+    }
+}
+
+impl Policy {
+    pub fn is_idempotent(method: &str) -> bool {
+        IDEMPOTENT.contains(&method)
+    }
+
+    /// The delay before the `retry`th retry: exponential from the base, capped at the
+    /// maximum, with jitter taking off up to half.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(16);
+        let delay = (self.backoff_base * 2u32.pow(exponent)).min(self.backoff_max);
+        let jitter = rand::random::<f64>() / 2.0;
+        let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_millis());
+        Duration::from_millis(millis - (millis as f64 * jitter) as u64)
+    }
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    requests: u32,
+    retries: u32,
+}
+
+/// Caps retries at a share of recent requests, so an outage does not multiply the load
+/// on whatever is left.
+#[derive(Debug)]
+pub struct Budget {
+    percent: u32,
+    window: Mutex<Window>,
+}
+
+impl Budget {
+    pub fn new(percent: u32) -> Budget {
+        Budget {
+            percent,
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            }),
+        }
+    }
+
+    fn current(&self) -> MutexGuard<Window> {
+        let mut window = self.window.lock().unwrap();
+        if window.start.elapsed() >= BUDGET_WINDOW {
+            *window = Window {
+                start: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+        window
+    }
+
+    pub fn request(&self) {
+        self.current().requests += 1;
+    }
+
+    /// Takes one retry from the budget, if any is left.
+    pub fn try_retry(&self) -> bool {
+        let mut window = self.current();
+        let allowed = (window.requests * self.percent / 100).max(BUDGET_MIN_RETRIES);
+        if window.retries < allowed {
+            window.retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use crate::{acl::HostPattern, config::flag, upstream, without_header, Request};
+use std::net::IpAddr;
+
+/// What the `Host` header sent to a backend says.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostHeader {
+    /// The host the client asked for.
+    Preserve,
+    /// The backend's own `host:port`.
+    Backend,
+    Fixed(String),
+}
+
+/// Where a route sends its requests.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    Addr(String, u16),
+    /// A `pool` by name, written `@name`.
+    Pool(String),
+}
+
+/// A reverse proxy route, configured as
+/// `route <host pattern|*> <path prefix> <host:port|@pool> [strip=on|off]
+/// [rewrite=<prefix>] [host=preserve|backend|<name>]`.
+///
+/// `strip=on` removes the matched prefix from the path, and `rewrite` replaces it.
+#[derive(Debug, Clone)]
+pub struct Route {
+    host: Option<HostPattern>,
+    pub prefix: String,
+    pub destination: Destination,
+    rewrite: Option<String>,
+    host_header: HostHeader,
+}
+
+impl Route {
+    pub fn parse(args: &[&str]) -> Result<Route, String> {
+        if args.len() < 3 {
+            return Err("expected a host pattern, a path prefix and a backend".to_string());
+        }
+        if !args[1].starts_with('/') {
+            return Err(format!("path prefix `{}` must start with /", args[1]));
+        }
+
+        let mut route = Route {
+            host: match args[0] {
+                "*" => None,
+                p => Some(HostPattern::parse(p)?),
+            },
+            prefix: args[1].to_string(),
+            destination: if args[2].starts_with('@') {
+                Destination::Pool(args[2][1..].to_string())
+            } else {
+                let (host, port) = upstream::parse_authority(args[2])?;
+                Destination::Addr(host, port)
+            },
+            rewrite: None,
+            host_header: HostHeader::Preserve,
+        };
+
+        for option in args[3..].iter() {
+            let mut split = option.splitn(2, '=');
+            let key = split.next().unwrap_or_default();
+            let value = split
+                .next()
+                .ok_or_else(|| format!("expected key=value, found `{}`", option))?;
+            match key {
+                "strip" if flag(&[value], 0)? => route.rewrite = Some("/".to_string()),
+                "strip" => route.rewrite = None,
+                "rewrite" => route.rewrite = Some(value.to_string()),
+                "host" => {
+                    route.host_header = match value {
+                        "preserve" => HostHeader::Preserve,
+                        "backend" => HostHeader::Backend,
+                        name => HostHeader::Fixed(name.to_string()),
+                    }
+                }
+                _ => return Err(format!("unknown route option `{}`", key)),
+            }
+        }
+
+        Ok(route)
+    }
+
+    /// Whether `path` is the prefix itself or lies below it.
+    fn matches_path(&self, path: &str) -> bool {
+        if !path.starts_with(self.prefix.as_str()) {
+            return false;
+        }
+        let rest = &path[self.prefix.len()..];
+        self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with(|c| c == '/' || c == '?')
+    }
+
+    /// Rewrites a client request into the request for `backend`, chosen for this route.
+    pub fn apply(&self, req: Request, client: IpAddr, backend: (String, u16)) -> Request {
+        let (backend_host, backend_port) = backend;
+        let client_host = req.header("Host").map(|h| h.to_string());
+
+        let path = match self.rewrite.as_ref() {
+            Some(rewrite) => {
+                let rest = &req.uri.path[self.prefix.len()..];
+                if rest.is_empty() || rest.starts_with('?') {
+                    format!("{}{}", rewrite, rest)
+                } else {
+                    format!(
+                        "{}/{}",
+                        rewrite.trim_end_matches('/'),
+                        rest.trim_start_matches('/')
+                    )
+                }
+            }
+            None => req.uri.path.clone(),
+        };
+
+        let host = match &self.host_header {
+            HostHeader::Preserve => client_host.clone(),
+            HostHeader::Backend => Some(upstream::format_authority(&backend_host, backend_port)),
+            HostHeader::Fixed(name) => Some(name.clone()),
+        };
+
+        let mut headers = without_header(&req.headers, "Host");
+        if let Some(host) = host {
+            headers.insert(0, format!("Host: {}", host));
+        }
+        let forwarded_for = match req.header("X-Forwarded-For") {
+            Some(chain) => format!("{}, {}", chain, client),
+            None => client.to_string(),
+        };
+        let mut headers = without_header(&headers, "X-Forwarded-For");
+        headers.push(format!("X-Forwarded-For: {}", forwarded_for));
+        if let Some(client_host) = client_host {
+            headers = without_header(&headers, "X-Forwarded-Host");
+            headers.push(format!("X-Forwarded-Host: {}", client_host));
+        }
+
+        let mut uri = req.uri.clone();
+        uri.host = backend_host;
+        uri.port = backend_port;
+        uri.path = path;
+
+        Request {
+            uri,
+            headers,
+            ..req
+        }
+    }
+}
+
+/// Picks the route with the longest prefix among those matching `host` and `path`, the
+/// first one configured on a tie.
+pub fn select<'a>(routes: &'a [Route], host: &str, path: &str) -> Option<&'a Route> {
+    routes
+        .iter()
+        .rev()
+        .filter(|r| r.host.as_ref().map_or(true, |p| p.matches(host)))
+        .filter(|r| r.matches_path(path))
+        .max_by_key(|r| r.prefix.len())
+}
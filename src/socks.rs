@@ -0,0 +1,242 @@
+use futures::compat::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::{io, net::TcpStream};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+
+const CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// The `REP` field of a SOCKS5 reply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+/// What the client asked for after the handshake.
+#[derive(Debug)]
+pub enum Command {
+    Connect { host: String, port: u16 },
+    /// `BIND`, `UDP ASSOCIATE` or anything else, already answered with an error.
+    Unsupported(u8),
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+async fn read_bytes(stream: TcpStream, len: usize) -> Result<(TcpStream, Vec<u8>), io::Error> {
+    await!(io::read_exact(stream, vec![0; len]).compat())
+}
+
+/// Picks an authentication method (RFC 1928 section 3), requiring username/password
+/// when `password` is set.
+///
+/// Returns the stream, and whether the client was willing to use the chosen method.
+pub async fn negotiate(
+    stream: TcpStream,
+    password: bool,
+) -> Result<(TcpStream, bool), io::Error> {
+    let (stream, head) = await!(read_bytes(stream, 2))?;
+    if head[0] != VERSION {
+        return Err(invalid("not a SOCKS5 client"));
+    }
+    let (stream, methods) = await!(read_bytes(stream, head[1] as usize))?;
+
+    let wanted = if password { PASSWORD } else { NO_AUTH };
+    let chosen = if methods.contains(&wanted) {
+        wanted
+    } else {
+        NO_ACCEPTABLE_METHOD
+    };
+    let (stream, _) = await!(io::write_all(stream, [VERSION, chosen]).compat())?;
+
+    Ok((stream, chosen != NO_ACCEPTABLE_METHOD))
+}
+
+/// Reads a username/password request (RFC 1929).
+pub async fn read_credentials(
+    stream: TcpStream,
+) -> Result<(TcpStream, String, String), io::Error> {
+    let (stream, head) = await!(read_bytes(stream, 2))?;
+    if head[0] != 0x01 {
+        return Err(invalid("unknown username/password version"));
+    }
+    let (stream, user) = await!(read_bytes(stream, head[1] as usize))?;
+    let (stream, len) = await!(read_bytes(stream, 1))?;
+    let (stream, password) = await!(read_bytes(stream, len[0] as usize))?;
+
+    Ok((
+        stream,
+        String::from_utf8_lossy(&user).to_string(),
+        String::from_utf8_lossy(&password).to_string(),
+    ))
+}
+
+/// Answers a username/password request; the client must close the connection on failure.
+pub async fn credentials_status(stream: TcpStream, ok: bool) -> Result<TcpStream, io::Error> {
+    let status = if ok { 0x00 } else { 0x01 };
+    let (stream, _) = await!(io::write_all(stream, [0x01, status]).compat())?;
+    Ok(stream)
+}
+
+/// Reads the request following authentication.
+///
+/// Unknown address types and commands other than `CONNECT` are refused here.
+pub async fn read_command(stream: TcpStream) -> Result<(TcpStream, Command), io::Error> {
+    let (stream, head) = await!(read_bytes(stream, 4))?;
+    if head[0] != VERSION {
+        return Err(invalid("not a SOCKS5 request"));
+    }
+
+    let (stream, host) = match head[3] {
+        ATYP_IPV4 => {
+            let (stream, addr) = await!(read_bytes(stream, 4))?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            (stream, ip.to_string())
+        }
+        ATYP_IPV6 => {
+            let (stream, addr) = await!(read_bytes(stream, 16))?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr);
+            (stream, Ipv6Addr::from(octets).to_string())
+        }
+        ATYP_DOMAIN => {
+            let (stream, len) = await!(read_bytes(stream, 1))?;
+            let (stream, name) = await!(read_bytes(stream, len[0] as usize))?;
+            (stream, String::from_utf8_lossy(&name).to_string())
+        }
+        _ => {
+            await!(reply(stream, Reply::AddressTypeNotSupported, None))?;
+            return Err(invalid("unknown address type"));
+        }
+    };
+    let (stream, port) = await!(read_bytes(stream, 2))?;
+    let port = (u16::from(port[0]) << 8) | u16::from(port[1]);
+
+    match head[1] {
+        CONNECT => Ok((stream, Command::Connect { host, port })),
+        command => {
+            let stream = await!(reply(stream, Reply::CommandNotSupported, None))?;
+            Ok((stream, Command::Unsupported(command)))
+        }
+    }
+}
+
+/// Sends a reply carrying the address the server bound for the client, if known.
+pub async fn reply(
+    stream: TcpStream,
+    reply: Reply,
+    bound: Option<SocketAddr>,
+) -> Result<TcpStream, io::Error> {
+    let bound = bound.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+
+    let mut buf = vec![VERSION, reply as u8, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.push((bound.port() >> 8) as u8);
+    buf.push(bound.port() as u8);
+
+    let (stream, _) = await!(io::write_all(stream, buf).compat())?;
+    Ok(stream)
+}
+
+/// Asks a SOCKS5 server to `CONNECT` to `host:port`, leaving name resolution to it.
+pub async fn connect(
+    stream: TcpStream,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+) -> Result<TcpStream, io::Error> {
+    let method = if credentials.is_some() { PASSWORD } else { NO_AUTH };
+    let (stream, _) = await!(io::write_all(stream, [VERSION, 1, method]).compat())?;
+    let (stream, chosen) = await!(read_bytes(stream, 2))?;
+    if chosen[0] != VERSION || chosen[1] != method {
+        return Err(invalid("SOCKS5 server refused the authentication method"));
+    }
+
+    let stream = match credentials {
+        Some((user, password)) => {
+            if user.len() > 255 || password.len() > 255 {
+                return Err(invalid("SOCKS5 username or password too long"));
+            }
+            let mut buf = vec![0x01, user.len() as u8];
+            buf.extend_from_slice(user.as_bytes());
+            buf.push(password.len() as u8);
+            buf.extend_from_slice(password.as_bytes());
+            let (stream, _) = await!(io::write_all(stream, buf).compat())?;
+            let (stream, status) = await!(read_bytes(stream, 2))?;
+            if status[1] != 0x00 {
+                return Err(invalid("SOCKS5 server rejected the credentials"));
+            }
+            stream
+        }
+        None => stream,
+    };
+
+    let mut buf = vec![VERSION, CONNECT, 0x00];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+        Err(_) if host.len() <= 255 => {
+            buf.push(ATYP_DOMAIN);
+            buf.push(host.len() as u8);
+            buf.extend_from_slice(host.as_bytes());
+        }
+        Err(_) => return Err(invalid("host name too long for SOCKS5")),
+    }
+    buf.push((port >> 8) as u8);
+    buf.push(port as u8);
+    let (stream, _) = await!(io::write_all(stream, buf).compat())?;
+
+    let (stream, head) = await!(read_bytes(stream, 4))?;
+    if head[0] != VERSION {
+        return Err(invalid("not a SOCKS5 reply"));
+    }
+    if head[1] != Reply::Succeeded as u8 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("SOCKS5 server replied {:#04x}", head[1]),
+        ));
+    }
+    let len = match head[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => {
+            let (stream, len) = await!(read_bytes(stream, 1))?;
+            let (stream, _) = await!(read_bytes(stream, len[0] as usize + 2))?;
+            return Ok(stream);
+        }
+        _ => return Err(invalid("unknown address type")),
+    };
+    let (stream, _) = await!(read_bytes(stream, len + 2))?;
+
+    Ok(stream)
+}
@@ -0,0 +1,241 @@
+use crate::{
+    acl::HostPattern,
+    config::{number, options},
+};
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{
+    prelude::{Async, AsyncRead, AsyncWrite, Future, Poll},
+    timer::Delay,
+};
+
+/// Pauses per second a shaped stream is split into, so its rate stays smooth.
+const SLICES_PER_SECOND: u64 = 10;
+
+/// How one connection's link is slowed down; the default leaves it alone.
+#[derive(Debug, Clone, Default)]
+pub struct Shaping {
+    /// Bytes per second from the proxy to the client.
+    pub download: Option<u64>,
+    /// Bytes per second from the client to the proxy.
+    pub upload: Option<u64>,
+    /// Added before each upstream response.
+    pub latency: Duration,
+    /// The latency varies by up to this much either way.
+    pub jitter: Duration,
+}
+
+impl Shaping {
+    fn delay(&self) -> Duration {
+        let spread = self.jitter.as_secs() as f64 + f64::from(self.jitter.subsec_nanos()) / 1e9;
+        let offset = (rand::random::<f64>() * 2.0 - 1.0) * spread;
+        let latency = self.latency.as_secs() as f64 + f64::from(self.latency.subsec_nanos()) / 1e9;
+        Duration::from_millis(((latency + offset).max(0.0) * 1000.0) as u64)
+    }
+}
+
+/// A `throttle <host pattern|*> [down=<bytes/s>] [up=<bytes/s>] [latency=<ms>]
+/// [jitter=<ms>]` directive.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Option<HostPattern>,
+    pub shaping: Shaping,
+}
+
+impl Rule {
+    pub fn parse(args: &[&str]) -> Result<Rule, String> {
+        let pattern = match args.get(0) {
+            Some(&"*") => None,
+            Some(p) => Some(HostPattern::parse(p)?),
+            None => return Err("expected a host pattern".to_string()),
+        };
+
+        let mut shaping = Shaping::default();
+        for (key, value) in options(&args[1..])? {
+            match key {
+                "down" => shaping.download = Some(rate(value)?),
+                "up" => shaping.upload = Some(rate(value)?),
+                "latency" => shaping.latency = Duration::from_millis(number(&[value], 0)?),
+                "jitter" => shaping.jitter = Duration::from_millis(number(&[value], 0)?),
+                _ => return Err(format!("unknown throttle option `{}`", key)),
+            }
+        }
+
+        Ok(Rule { pattern, shaping })
+    }
+}
+
+fn rate(value: &str) -> Result<u64, String> {
+    number(&[value], 0)
+        .ok()
+        .filter(|rate| *rate > 0)
+        .ok_or_else(|| format!("invalid rate `{}`", value))
+}
+
+/// Returns the shaping of the first rule matching `host`; without a host yet, only `*`
+/// rules match.
+pub fn select(rules: &[Rule], host: Option<&str>) -> Shaping {
+    rules
+        .iter()
+        .find(|r| match (r.pattern.as_ref(), host) {
+            (None, _) => true,
+            (Some(pattern), Some(host)) => pattern.matches(host),
+            (Some(_), None) => false,
+        })
+        .map(|r| r.shaping.clone())
+        .unwrap_or_default()
+}
+
+/// The shaping shared by every stream of one connection, so it can follow the route
+/// once the request says where it goes.
+#[derive(Debug, Clone, Default)]
+pub struct Link {
+    shaping: Arc<RwLock<Shaping>>,
+}
+
+impl Link {
+    pub fn new(shaping: Shaping) -> Link {
+        Link {
+            shaping: Arc::new(RwLock::new(shaping)),
+        }
+    }
+
+    pub fn set(&self, shaping: Shaping) {
+        *self.shaping.write().unwrap() = shaping;
+    }
+
+    fn get(&self) -> Shaping {
+        self.shaping.read().unwrap().clone()
+    }
+
+    /// Wraps the client's side: reads are paced as uploads and writes as downloads.
+    pub fn client<S>(&self, stream: S) -> Throttled<S> {
+        Throttled::new(stream, self.clone(), Side::Client)
+    }
+
+    /// Wraps an upstream side: the first read after each write waits out the latency.
+    pub fn upstream<S>(&self, stream: S) -> Throttled<S> {
+        Throttled::new(stream, self.clone(), Side::Upstream)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Client,
+    Upstream,
+}
+
+/// A stream slowed down as its `Link` says.
+///
+/// A shaped direction moves at most a tenth of a second's worth of bytes at a time, then
+/// pauses for as long as they take at the configured rate.
+pub struct Throttled<S> {
+    inner: S,
+    link: Link,
+    side: Side,
+    read_pause: Option<Delay>,
+    write_pause: Option<Delay>,
+    latency_due: bool,
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, link: Link, side: Side) -> Throttled<S> {
+        Throttled {
+            inner,
+            link,
+            side,
+            read_pause: None,
+            write_pause: None,
+            latency_due: side == Side::Upstream,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+/// Fails with `WouldBlock`, which tokio reads as not ready, while `pause` is pending.
+fn wait(pause: &mut Option<Delay>) -> io::Result<()> {
+    if let Some(delay) = pause.as_mut() {
+        if let Ok(Async::NotReady) = delay.poll() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+    }
+    *pause = None;
+    Ok(())
+}
+
+/// The part of `len` bytes that may move at once at `rate`.
+fn slice(len: usize, rate: Option<u64>) -> usize {
+    match rate {
+        Some(rate) => len.min((rate / SLICES_PER_SECOND).max(1) as usize),
+        None => len,
+    }
+}
+
+/// Starts the pause that `n` bytes just moved take at `rate`.
+fn pace(pause: &mut Option<Delay>, n: usize, rate: Option<u64>) {
+    if let Some(rate) = rate.filter(|_| n > 0) {
+        let nanos = n as u64 * 1_000_000_000 / rate;
+        *pause = Some(Delay::new(Instant::now() + Duration::from_nanos(nanos)));
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        wait(&mut self.read_pause)?;
+        let shaping = self.link.get();
+
+        if self.latency_due {
+            self.latency_due = false;
+            let delay = shaping.delay();
+            if delay > Duration::from_millis(0) {
+                self.read_pause = Some(Delay::new(Instant::now() + delay));
+                wait(&mut self.read_pause)?;
+            }
+        }
+
+        let rate = match self.side {
+            Side::Client => shaping.upload,
+            Side::Upstream => None,
+        };
+        let len = slice(buf.len(), rate);
+        let n = self.inner.read(&mut buf[..len])?;
+        pace(&mut self.read_pause, n, rate);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        wait(&mut self.write_pause)?;
+
+        let rate = match self.side {
+            Side::Client => self.link.get().download,
+            Side::Upstream => None,
+        };
+        let len = slice(buf.len(), rate);
+        let n = self.inner.write(&buf[..len])?;
+        pace(&mut self.write_pause, n, rate);
+        if self.side == Side::Upstream {
+            self.latency_due = true;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Throttled<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Throttled<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
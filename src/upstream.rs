@@ -0,0 +1,246 @@
+use crate::{acl::HostPattern, *};
+use futures::compat::*;
+use std::net::{IpAddr, SocketAddr};
+use tokio::{io, net::TcpStream};
+
+/// Where connections to a destination go.
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    Direct,
+    /// Direct, but only to addresses resolved and checked beforehand, so the name cannot
+    /// be re-resolved somewhere else in between.
+    Pinned(Vec<IpAddr>),
+    /// An HTTP proxy, spoken to with absolute-form requests and `CONNECT`.
+    Http {
+        host: String,
+        port: u16,
+        /// Base64 `user:password` sent as Basic `Proxy-Authorization`.
+        credentials: Option<String>,
+    },
+    /// A SOCKS5 server, which also resolves the destination name.
+    Socks5 {
+        host: String,
+        port: u16,
+        credentials: Option<(String, String)>,
+    },
+}
+
+impl Upstream {
+    /// Makes a direct upstream connect only to `addrs`.
+    pub fn pin(self, addrs: Vec<IpAddr>) -> Upstream {
+        match self {
+            Upstream::Direct => Upstream::Pinned(addrs),
+            upstream => upstream,
+        }
+    }
+
+    /// The host a connection for a request to `host` is opened to.
+    pub fn connect_host<'a>(&'a self, host: &'a str) -> &'a str {
+        match self {
+            Upstream::Direct | Upstream::Pinned(_) => host,
+            Upstream::Http { host, .. } | Upstream::Socks5 { host, .. } => host,
+        }
+    }
+}
+
+/// A `parent_proxy <host pattern|*> <DIRECT|[socks5://]host:port> [user:password]`
+/// directive.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pattern: Option<HostPattern>,
+    pub upstream: Upstream,
+}
+
+impl Route {
+    pub fn parse(args: &[&str]) -> Result<Route, String> {
+        if args.len() < 2 || args.len() > 3 {
+            return Err(
+                "expected a host pattern, DIRECT or host:port, and credentials".to_string(),
+            );
+        }
+
+        let pattern = match args[0] {
+            "*" => None,
+            p => Some(HostPattern::parse(p)?),
+        };
+        let upstream = if args[1] == "DIRECT" {
+            Upstream::Direct
+        } else if args[1].starts_with("socks5://") {
+            let (host, port) = parse_authority(&args[1]["socks5://".len()..])?;
+            let credentials = match args.get(2) {
+                Some(c) => {
+                    let mut split = c.splitn(2, ':');
+                    let user = split.next().unwrap_or_default().to_string();
+                    let password = split
+                        .next()
+                        .ok_or_else(|| format!("expected user:password, found `{}`", c))?
+                        .to_string();
+                    Some((user, password))
+                }
+                None => None,
+            };
+            Upstream::Socks5 {
+                host,
+                port,
+                credentials,
+            }
+        } else {
+            let (host, port) = parse_authority(args[1].trim_start_matches("http://"))?;
+            Upstream::Http {
+                host,
+                port,
+                credentials: args.get(2).map(|c| base64::encode(c)),
+            }
+        };
+
+        Ok(Route { pattern, upstream })
+    }
+}
+
+pub fn parse_authority(s: &str) -> Result<(String, u16), String> {
+    let mut split = s.rsplitn(2, ':');
+    let port = split
+        .next()
+        .and_then(|p| p.parse().ok())
+        .ok_or_else(|| format!("expected host:port, found `{}`", s))?;
+    let host = split
+        .next()
+        .filter(|h| !h.is_empty())
+        .ok_or_else(|| format!("expected host:port, found `{}`", s))?;
+    Ok((host.trim_start_matches('[').trim_end_matches(']').to_string(), port))
+}
+
+/// Returns the upstream of the first route matching `host`, or `Direct`.
+pub fn select(routes: &[Route], host: &str) -> Upstream {
+    routes
+        .iter()
+        .find(|r| r.pattern.as_ref().map_or(true, |p| p.matches(host)))
+        .map(|r| r.upstream.clone())
+        .unwrap_or(Upstream::Direct)
+}
+
+/// Connects straight to `host:port`, trying its addresses as `happy_eyeballs` orders them.
+pub async fn connect(host: String, port: u16) -> Result<TcpStream, HttpError> {
+    let addrs = await!(dns::resolve_addrs(host, port))?;
+    await!(connect_addrs(addrs))
+}
+
+async fn connect_addrs(addrs: Vec<SocketAddr>) -> Result<TcpStream, HttpError> {
+    await!(happy_eyeballs::connect(addrs).compat())
+        .map_err(|e| HttpError::BadGateway(format!("connecting failed: {:?}", e)))
+}
+
+fn socket_addrs(addrs: Vec<IpAddr>, port: u16) -> Vec<SocketAddr> {
+    addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()
+}
+
+/// Opens a connection for forwarding `req`, rewriting it for the parent proxy if any.
+pub async fn open(upstream: Upstream, req: Request) -> Result<(TcpStream, Request), HttpError> {
+    match upstream {
+        Upstream::Direct => {
+            let stream = await!(connect(req.uri.host.clone(), req.uri.port))?;
+            Ok((stream, req))
+        }
+        Upstream::Pinned(addrs) => {
+            let stream = await!(connect_addrs(socket_addrs(addrs, req.uri.port)))?;
+            Ok((stream, req))
+        }
+        Upstream::Http {
+            host,
+            port,
+            credentials,
+        } => {
+            let stream = await!(connect(host, port))?;
+            let mut req = req;
+            if let Some(credentials) = credentials {
+                req.headers.push(format!("Proxy-Authorization: Basic {}", credentials));
+            }
+            Ok((stream, req))
+        }
+        socks5 @ Upstream::Socks5 { .. } => {
+            let stream = await!(open_tunnel(socks5, req.uri.host.clone(), req.uri.port))?;
+            Ok((stream, req))
+        }
+    }
+}
+
+/// Opens a raw byte stream to `host:port`, through `CONNECT` when behind a parent proxy.
+pub async fn open_tunnel(
+    upstream: Upstream,
+    host: String,
+    port: u16,
+) -> Result<TcpStream, HttpError> {
+    match upstream {
+        Upstream::Direct => await!(connect(host, port)),
+        Upstream::Pinned(addrs) => await!(connect_addrs(socket_addrs(addrs, port))),
+        Upstream::Http {
+            host: proxy_host,
+            port: proxy_port,
+            credentials,
+        } => {
+            let stream = await!(connect(proxy_host, proxy_port))?;
+            let authority = format_authority(&host, port);
+            let mut head = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", authority, authority);
+            if let Some(credentials) = credentials {
+                head += &format!("Proxy-Authorization: Basic {}\r\n", credentials);
+            }
+            head += "\r\n";
+
+            let (stream, _) = await!(io::write_all(stream, head).compat())
+                .map_err(|e| HttpError::BadGateway(format!("sending CONNECT failed: {:?}", e)))?;
+            let (stream, head) = await!(read_head_unbuffered(stream))?;
+
+            let status = head
+                .split_whitespace()
+                .nth(1)
+                .and_then(|s| s.parse::<u16>().ok())
+                .unwrap_or(0);
+            if status / 100 != 2 {
+                let line = head.lines().next().unwrap_or_default().to_string();
+                return Err(HttpError::BadGateway(format!(
+                    "parent proxy refused CONNECT {}: {}",
+                    authority, line
+                )));
+            }
+
+            Ok(stream)
+        }
+        Upstream::Socks5 {
+            host: proxy_host,
+            port: proxy_port,
+            credentials,
+        } => {
+            let stream = await!(connect(proxy_host, proxy_port))?;
+            await!(socks::connect(stream, host.clone(), port, credentials)).map_err(|e| {
+                let authority = format_authority(&host, port);
+                HttpError::BadGateway(format!("SOCKS5 CONNECT {} failed: {:?}", authority, e))
+            })
+        }
+    }
+}
+
+pub fn format_authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Reads a response head a byte at a time, so nothing after it is consumed from `stream`.
+async fn read_head_unbuffered(stream: TcpStream) -> Result<(TcpStream, String), HttpError> {
+    let mut stream = stream;
+    let mut head = vec![];
+
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        if head.len() > 64 * 1024 {
+            return Err(HttpError::BadGateway("response head too long".to_string()));
+        }
+        let (s, byte) = await!(io::read_exact(stream, [0u8; 1]).compat())
+            .map_err(|e| HttpError::BadGateway(format!("reading response failed: {:?}", e)))?;
+        stream = s;
+        head.push(byte[0]);
+    }
+
+    Ok((stream, String::from_utf8_lossy(&head).to_string()))
+}
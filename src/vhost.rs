@@ -0,0 +1,101 @@
+use crate::config::{flag, Config};
+
+/// A name-based virtual host, configured as
+/// `vhost <name> <root> [cgi_bin=<prefix>] [gzip=on|off] [gzip_static=on|off]`.
+///
+/// `<name>` is an exact host name, a wildcard such as `*.example.com`, or `*` for the
+/// default host.
+#[derive(Debug, Clone)]
+pub struct VirtualHost {
+    pub pattern: String,
+    pub root: String,
+    pub cgi_bin: Option<String>,
+    pub gzip: Option<bool>,
+    pub gzip_static: Option<bool>,
+}
+
+impl VirtualHost {
+    pub fn parse(args: &[&str]) -> Result<VirtualHost, String> {
+        if args.len() < 2 {
+            return Err("expected a host name and a document root".to_string());
+        }
+
+        let mut vhost = VirtualHost {
+            pattern: args[0].to_lowercase(),
+            root: args[1].trim_end_matches('/').to_string(),
+            cgi_bin: None,
+            gzip: None,
+            gzip_static: None,
+        };
+
+        for option in args[2..].iter() {
+            let mut split = option.splitn(2, '=');
+            let key = split.next().unwrap_or_default();
+            let value = split
+                .next()
+                .ok_or_else(|| format!("expected key=value, found `{}`", option))?;
+            match key {
+                "cgi_bin" => vhost.cgi_bin = Some(value.trim_end_matches('/').to_string()),
+                "gzip" => vhost.gzip = Some(flag(&[value], 0)?),
+                "gzip_static" => vhost.gzip_static = Some(flag(&[value], 0)?),
+                _ => return Err(format!("unknown vhost option `{}`", key)),
+            }
+        }
+
+        Ok(vhost)
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        if self.pattern.starts_with("*.") {
+            host.ends_with(&self.pattern[1..])
+        } else {
+            self.pattern == host
+        }
+    }
+}
+
+/// Settings in effect for one request after virtual host selection.
+#[derive(Debug, Clone)]
+pub struct Site {
+    pub root: String,
+    pub cgi_bin: Option<String>,
+    pub gzip: bool,
+    pub gzip_static: bool,
+}
+
+impl Site {
+    /// Picks the virtual host for `host`: an exact name first, then the longest matching
+    /// wildcard, then `*`. Without any match the global settings apply.
+    pub fn select(config: &Config, host: &str) -> Site {
+        let host = host.to_lowercase();
+        let host = host.trim_end_matches('.');
+
+        let exact = config
+            .vhosts
+            .iter()
+            .find(|v| !v.pattern.starts_with('*') && v.matches(host));
+        let wildcard = config
+            .vhosts
+            .iter()
+            .filter(|v| v.pattern.starts_with("*.") && v.matches(host))
+            .max_by_key(|v| v.pattern.len());
+        let default = config.vhosts.iter().find(|v| v.pattern == "*");
+
+        let site = Site {
+            root: config.default_root.clone(),
+            cgi_bin: config.cgi_bin.clone(),
+            gzip: config.gzip,
+            gzip_static: config.gzip_static,
+        };
+
+        match exact.or(wildcard).or(default) {
+            Some(vhost) => Site {
+                root: vhost.root.clone(),
+                cgi_bin: vhost.cgi_bin.clone().or(site.cgi_bin),
+                gzip: vhost.gzip.unwrap_or(site.gzip),
+                gzip_static: vhost.gzip_static.unwrap_or(site.gzip_static),
+            },
+            None => site,
+        }
+    }
+}